@@ -0,0 +1,128 @@
+use meic_mq::messages::admin::SubscriberInfo;
+use meic_mq::messages::hello::{ FEATURE_COMPRESSION, FEATURE_HEADERS };
+use meic_mq::messages::topic::DescribeReply;
+use serde::Serialize;
+
+use std::env;
+use std::process;
+
+pub const USAGE_MESSAGE: &str = "USAGE:\nmeic-admin [--json] <command> [argument]\ncommand: one of
+  topics                 list the topics
+  describe <topic>       show the posts, size and subscriber offsets of a topic
+  subscribers [topic]    list the subscribers, of a single topic if given
+  lag [topic]            show how many posts each subscriber has not read yet
+  evict <sub_id>         forcibly unsubscribe a subscriber
+  purge <topic>          drop every post held for a topic
+  snapshot               make the broker save its state now
+  version                show the protocol version and features agreed with the broker";
+
+#[derive(Serialize)]
+struct SubscriberLag<'a> {
+    sub_id: &'a str,
+    topic: &'a str,
+    lag: u64
+}
+
+fn main() {
+
+    // Credentials and CURVE keys are taken from the environment
+    if let Err(err) = meic_mq::configure_from_env() {
+        eprintln!("Couldn't set up the connection to the broker: {}", err);
+        process::exit(1);
+    }
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let json = args.iter().any(|arg| arg == "--json");
+    args.retain(|arg| arg != "--json");
+
+    if args.is_empty() {
+        println!("Incorrect usage of the command line interface: missing arguments.");
+        println!("{}", USAGE_MESSAGE);
+        process::exit(1);
+    }
+
+    let command: &str = &args[0];
+    let argument: Option<String> = args.get(1).cloned();
+
+    let result = match (command, argument) {
+        ("topics", None) => meic_mq::list_topics().map(|topics| {
+            print_output(json, &topics, || topics.iter().for_each(|topic| println!("{}", topic)))
+        }),
+        ("describe", Some(topic)) => meic_mq::describe_topic(topic).map(|description| {
+            print_output(json, &description, || print_description(&description))
+        }),
+        ("subscribers", topic) => meic_mq::list_subscribers(topic).map(|subscribers| {
+            print_output(json, &subscribers, || print_subscribers(&subscribers))
+        }),
+        ("lag", topic) => meic_mq::list_subscribers(topic).map(|subscribers| {
+            let lags: Vec<SubscriberLag> = subscribers.iter()
+                .map(|sub| SubscriberLag { sub_id: &sub.sub_id, topic: &sub.topic, lag: sub.lag })
+                .collect();
+            print_output(json, &lags, || {
+                println!("{:<24} {:<24} {:>8}", "SUBSCRIBER", "TOPIC", "LAG");
+                lags.iter().for_each(|lag| println!("{:<24} {:<24} {:>8}", lag.sub_id, lag.topic, lag.lag));
+            })
+        }),
+        ("evict", Some(sub_id)) => meic_mq::evict_subscriber(sub_id).map(|reply| {
+            print_output(json, &reply, || println!("Evicted subscriber {} from topic {}", reply.sub_id, reply.topic))
+        }),
+        ("purge", Some(topic)) => meic_mq::purge_topic(topic).map(|reply| {
+            print_output(json, &reply, || println!("Purged {} posts from topic {}", reply.purged_posts, reply.topic))
+        }),
+        ("snapshot", None) => meic_mq::snapshot().map(|reply| {
+            print_output(json, &reply, || println!("Saved {} bytes to {} in {} ms", reply.bytes, reply.path, reply.duration_ms))
+        }),
+        ("version", None) => {
+            let features = [FEATURE_HEADERS, FEATURE_COMPRESSION].map(str::to_owned).to_vec();
+            meic_mq::hello(features).map(|reply| {
+                print_output(json, &reply, || println!("Protocol version {} with features {:?}", reply.version, reply.features))
+            })
+        },
+        _ => {
+            println!("Incorrect usage of the command line interface: unknown command or wrong arguments.");
+            println!("{}", USAGE_MESSAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("Broker refused the request: {}", err);
+        process::exit(2);
+    }
+}
+
+fn print_output<T: Serialize>(json: bool, value: &T, print_human: impl FnOnce()) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    } else {
+        print_human();
+    }
+}
+
+fn print_description(description: &DescribeReply) {
+    println!("Topic:           {}", description.topic);
+    println!("Default TTL:     {}", description.settings.default_ttl_secs.map_or("none".to_owned(), |ttl| format!("{} s", ttl)));
+    println!("Max deliveries:  {}", description.settings.max_delivery_attempts.map_or("none".to_owned(), |max| max.to_string()));
+    println!("Retained posts:  {} ({} scheduled)", description.retained_posts, description.scheduled_posts);
+    println!("Size:            {} bytes", description.byte_size);
+
+    let mut priorities: Vec<(&String, &u64)> = description.post_counters.iter().collect();
+    priorities.sort();
+    for (priority, post_counter) in priorities {
+        println!("Priority {:<6}  {} posts published", priority, post_counter);
+    }
+
+    let mut subscribers: Vec<(&String, &_)> = description.subscriber_offsets.iter().collect();
+    subscribers.sort_by(|a, b| a.0.cmp(b.0));
+    for (sub_id, last_read_posts) in subscribers {
+        println!("Subscriber {:<20} last read posts {:?}", sub_id, last_read_posts);
+    }
+}
+
+fn print_subscribers(subscribers: &[SubscriberInfo]) {
+    println!("{:<24} {:<24} {:<12} {:<10} {:>12} {:>8}", "SUBSCRIBER", "TOPIC", "STATUS", "SESSION", "LAST SEEN", "LAG");
+    for sub in subscribers {
+        println!("{:<24} {:<24} {:<12} {:<10} {:>12} {:>8}",
+            sub.sub_id, sub.topic, sub.status, format!("{:?}", sub.session), sub.last_seen, sub.lag);
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Each input is a sequence of requests, each made of the frames received by the broker socket
+fuzz_target!(|requests: Vec<Vec<Vec<u8>>>| {
+    broker::fuzz_requests(requests);
+});
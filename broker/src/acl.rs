@@ -0,0 +1,163 @@
+use serde::{ Serialize, Deserialize };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Publish,
+    Subscribe,
+    Admin
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny
+}
+
+// Rules are checked in order and the first one matching a request decides, the default applies when none does
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub default: Effect,
+    pub rules: Vec<AclRule>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    // Patterns where * matches any text, unsigned requests have an empty client id
+    #[serde(default = "match_all")]
+    pub client: String,
+    // Commands that are not about a single topic have an empty topic
+    #[serde(default = "match_all")]
+    pub topic: String,
+    pub actions: Vec<Action>,
+    pub effect: Effect
+}
+
+fn match_all() -> String {
+    "*".to_owned()
+}
+
+impl Default for AclConfig {
+    fn default() -> AclConfig {
+        AclConfig { default: Effect::Allow, rules: Vec::new() }
+    }
+}
+
+impl AclConfig {
+    pub fn allows(&self, client_id: Option<&str>, action: Action, topic: Option<&str>) -> bool {
+        let client_id = client_id.unwrap_or("");
+        let topic = topic.unwrap_or("");
+        let effect = self.rules.iter()
+            .find(|rule| rule.actions.contains(&action) && matches_pattern(&rule.client, client_id) && matches_pattern(&rule.topic, topic))
+            .map_or(self.default, |rule| rule.effect);
+        effect == Effect::Allow
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.rules.iter().position(|rule| rule.actions.is_empty()) {
+            Some(index) => Err(format!("ACL rule {} has no actions", index + 1)),
+            None => Ok(())
+        }
+    }
+}
+
+// Glob matching where * stands for any sequence of characters, including none, and every other character, ? included, for itself
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let middle: Vec<&str> = parts.collect();
+    let Some((last, middle)) = middle.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(client: &str, topic: &str, actions: &[Action], effect: Effect) -> AclRule {
+        AclRule { client: client.to_owned(), topic: topic.to_owned(), actions: actions.to_vec(), effect }
+    }
+
+    #[test]
+    fn star_matches_any_text() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("*", "orders"));
+        assert!(matches_pattern("orders.*", "orders."));
+        assert!(matches_pattern("orders.*", "orders.eu"));
+        assert!(matches_pattern("*.eu", "orders.eu"));
+        assert!(matches_pattern("o*s.*u", "orders.eu"));
+        assert!(!matches_pattern("orders.*", "order"));
+        assert!(!matches_pattern("*.eu", "orders.us"));
+        assert!(!matches_pattern("a*a", "a"));
+    }
+
+    #[test]
+    fn question_mark_is_literal() {
+        assert!(matches_pattern("orders?", "orders?"));
+        assert!(!matches_pattern("orders?", "orders1"));
+        assert!(!matches_pattern("orders?", "orders"));
+    }
+
+    #[test]
+    fn other_patterns_match_exactly() {
+        assert!(matches_pattern("orders", "orders"));
+        assert!(!matches_pattern("orders", "orders.eu"));
+        assert!(!matches_pattern("orders", "Orders"));
+        assert!(matches_pattern("", ""));
+        assert!(!matches_pattern("", "orders"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = AclConfig {
+            default: Effect::Allow,
+            rules: vec![
+                rule("alice", "orders", &[Action::Publish], Effect::Allow),
+                rule("*", "orders", &[Action::Publish, Action::Subscribe], Effect::Deny),
+                rule("*", "*", &[Action::Publish], Effect::Allow)
+            ]
+        };
+        assert!(acl.allows(Some("alice"), Action::Publish, Some("orders")));
+        assert!(!acl.allows(Some("bob"), Action::Publish, Some("orders")));
+        assert!(!acl.allows(Some("alice"), Action::Subscribe, Some("orders")));
+        assert!(acl.allows(Some("bob"), Action::Publish, Some("invoices")));
+    }
+
+    #[test]
+    fn default_applies_when_no_rule_matches() {
+        let mut acl = AclConfig {
+            default: Effect::Deny,
+            rules: vec![rule("alice", "*", &[Action::Subscribe], Effect::Allow)]
+        };
+        assert!(acl.allows(Some("alice"), Action::Subscribe, Some("orders")));
+        assert!(!acl.allows(Some("alice"), Action::Admin, None));
+        assert!(!acl.allows(None, Action::Subscribe, Some("orders")));
+        acl.default = Effect::Allow;
+        assert!(acl.allows(None, Action::Subscribe, Some("orders")));
+        assert!(AclConfig::default().allows(None, Action::Admin, None));
+    }
+
+    #[test]
+    fn unsigned_requests_have_an_empty_client_id() {
+        let acl = AclConfig {
+            default: Effect::Allow,
+            rules: vec![rule("", "*", &[Action::Publish], Effect::Deny)]
+        };
+        assert!(!acl.allows(None, Action::Publish, Some("orders")));
+        assert!(acl.allows(Some("alice"), Action::Publish, Some("orders")));
+    }
+}
@@ -0,0 +1,564 @@
+use meic_mq::curve::KeyPair;
+use meic_mq::messages::subscribe::SessionType;
+use serde::{ Serialize, Serializer, Deserialize };
+use tracing_subscriber::EnvFilter;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant, SystemTime };
+
+use super::acl::AclConfig;
+use super::{ DURABLE_SESSION_TIMEOUT_SECS, EPHEMERAL_SESSION_TIMEOUT_SECS, DEFAULT_VISIBILITY_TIMEOUT_MS, MAX_TIMESTAMP };
+
+const CONFIG_FILE_VAR: &str = "BROKER_CONFIG";
+const BIND_VAR: &str = "BROKER_BIND";
+const DATA_DIR_VAR: &str = "BROKER_DATA_DIR";
+const LOG_LEVEL_VAR: &str = "BROKER_LOG_LEVEL";
+const AUTO_CREATE_TOPICS_VAR: &str = "BROKER_AUTO_CREATE_TOPICS";
+const METRICS_PORT_VAR: &str = "BROKER_METRICS_PORT";
+const FSYNC_VAR: &str = "BROKER_FSYNC";
+const SNAPSHOT_INTERVAL_VAR: &str = "BROKER_SNAPSHOT_INTERVAL_MS";
+const CURVE_PUBLIC_KEY_VAR: &str = "BROKER_CURVE_PUBLIC_KEY";
+const CURVE_SECRET_KEY_VAR: &str = "BROKER_CURVE_SECRET_KEY";
+const DEFAULT_METRICS_PORT: u16 = 9464;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+const DEFAULT_MAX_DELAY_MS: u64 = 24 * 60 * 60 * 1000;
+// Keeps the redelivery times of the state file within the range BSON can store
+const MAX_DELAY_LIMIT_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Printed instead of the secrets when the configuration is shown
+const REDACTED: &str = "<redacted>";
+
+// Requests that create the topic they mention when it does not exist yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AutoCreatePolicy {
+    #[serde(rename = "never")]
+    Never,
+    #[serde(rename = "subscribe")]
+    OnSubscribe,
+    #[serde(rename = "always")]
+    Always
+}
+
+impl AutoCreatePolicy {
+    fn parse(value: &str) -> Option<AutoCreatePolicy> {
+        match value.to_lowercase().as_str() {
+            "never" => Some(AutoCreatePolicy::Never),
+            "subscribe" => Some(AutoCreatePolicy::OnSubscribe),
+            "always" => Some(AutoCreatePolicy::Always),
+            _ => None
+        }
+    }
+
+    pub fn on_subscribe(&self) -> bool {
+        *self != AutoCreatePolicy::Never
+    }
+
+    pub fn on_publish(&self) -> bool {
+        *self == AutoCreatePolicy::Always
+    }
+
+    pub fn on_dead_letter(&self) -> bool {
+        *self != AutoCreatePolicy::Never
+    }
+}
+
+// Whether every state snapshot is flushed to disk before the broker carries on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    Never,
+    Always
+}
+
+impl FsyncPolicy {
+    fn parse(value: &str) -> Option<FsyncPolicy> {
+        match value.to_lowercase().as_str() {
+            "never" => Some(FsyncPolicy::Never),
+            "always" => Some(FsyncPolicy::Always),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Endpoints the broker socket is bound to
+    pub bind: Vec<String>,
+    // Directory holding the state file and the audit log
+    pub data_dir: String,
+    // Used when RUST_LOG is not set
+    pub log_level: String,
+    pub auto_create_topics: AutoCreatePolicy,
+    // Time spent answering pending requests when shutting down
+    pub shutdown_timeout_ms: u64,
+    pub metrics: MetricsConfig,
+    pub storage: StorageConfig,
+    pub retention: RetentionConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub acl: AclConfig,
+    pub curve: CurveConfig
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub port: u16
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub fsync: FsyncPolicy,
+    // Minimum time between state snapshots, 0 saves the state after every change
+    pub snapshot_interval_ms: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    // Applied to posts published without a time-to-live to topics that do not set one
+    pub default_ttl_secs: Option<u64>,
+    pub durable_session_timeout_secs: u64,
+    pub ephemeral_session_timeout_secs: u64,
+    pub visibility_timeout_ms: u64
+}
+
+// Requests going over a limit are refused, missing limits are not enforced
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_payload_bytes: Option<u64>,
+    pub max_topics: Option<u64>,
+    pub max_posts_per_topic: Option<u64>,
+    // Longest requeue delay or visibility timeout a subscriber may ask for, always enforced
+    pub max_delay_ms: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Refuses unsigned requests, signed ones are always verified
+    pub required: bool,
+    // How far the timestamp of a signed request may be from the broker clock
+    pub max_clock_skew_ms: u64,
+    // Shared secret of each client id
+    #[serde(serialize_with = "serialize_redacted_secrets")]
+    pub clients: HashMap<String, String>
+}
+
+// Key pair of the broker, connections are encrypted with CURVE when it is set
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CurveConfig {
+    pub public_key: Option<String>,
+    #[serde(serialize_with = "serialize_redacted_secret")]
+    pub secret_key: Option<String>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: vec!["tcp://*:5555".to_owned()],
+            data_dir: ".".to_owned(),
+            log_level: "info".to_owned(),
+            auto_create_topics: AutoCreatePolicy::OnSubscribe,
+            shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS,
+            metrics: MetricsConfig::default(),
+            storage: StorageConfig::default(),
+            retention: RetentionConfig::default(),
+            limits: LimitsConfig::default(),
+            auth: AuthConfig::default(),
+            acl: AclConfig::default(),
+            curve: CurveConfig::default()
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig { enabled: true, port: DEFAULT_METRICS_PORT }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig { max_payload_bytes: None, max_topics: None, max_posts_per_topic: None, max_delay_ms: DEFAULT_MAX_DELAY_MS }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig { required: false, max_clock_skew_ms: DEFAULT_MAX_CLOCK_SKEW_MS, clients: HashMap::new() }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig { fsync: FsyncPolicy::Never, snapshot_interval_ms: 0 }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            default_ttl_secs: None,
+            durable_session_timeout_secs: DURABLE_SESSION_TIMEOUT_SECS,
+            ephemeral_session_timeout_secs: EPHEMERAL_SESSION_TIMEOUT_SECS,
+            visibility_timeout_ms: DEFAULT_VISIBILITY_TIMEOUT_MS
+        }
+    }
+}
+
+impl CurveConfig {
+    pub fn keypair(&self) -> Result<Option<KeyPair>, String> {
+        match (&self.public_key, &self.secret_key) {
+            (Some(public_key), Some(secret_key)) => KeyPair::new(public_key.clone(), secret_key.clone()).map(Some),
+            (None, None) => Ok(None),
+            _ => Err("The CURVE public and secret keys must be set together".to_owned())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandLine {
+    pub config_file: Option<String>,
+    pub bind: Vec<String>,
+    pub data_dir: Option<String>,
+    pub log_level: Option<String>,
+    pub metrics_port: Option<String>,
+    pub print_config: bool,
+    pub help: bool
+}
+
+impl CommandLine {
+    pub fn parse(arguments: &[String]) -> Result<CommandLine, String> {
+        let mut command_line = CommandLine::default();
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            let mut value = || arguments.next().cloned().ok_or(format!("Missing value for {}", argument));
+            match argument.as_str() {
+                "--config" => command_line.config_file = Some(value()?),
+                "--bind" => command_line.bind.push(value()?),
+                "--data-dir" => command_line.data_dir = Some(value()?),
+                "--log-level" => command_line.log_level = Some(value()?),
+                "--metrics-port" => command_line.metrics_port = Some(value()?),
+                "--print-config" => command_line.print_config = true,
+                "--help" | "-h" => command_line.help = true,
+                _ => return Err(format!("Unknown option {}", argument))
+            }
+        }
+        Ok(command_line)
+    }
+
+    fn config_file(&self) -> Option<String> {
+        self.config_file.clone().or_else(|| env::var(CONFIG_FILE_VAR).ok())
+    }
+}
+
+impl Config {
+    // Defaults, then the configuration file, then environment variables and then command-line options
+    pub fn load(command_line: &CommandLine) -> Result<Config, String> {
+        let mut config = match command_line.config_file() {
+            Some(path) => {
+                let contents = fs::read_to_string(&path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+                toml::from_str(&contents).map_err(|err| format!("Couldn't parse {}: {}", path, err))?
+            },
+            None => Config::default()
+        };
+
+        config.apply_env()?;
+        config.apply_command_line(command_line)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        if let Ok(value) = env::var(BIND_VAR) {
+            self.bind = value.split(',').map(|endpoint| endpoint.trim().to_owned()).collect();
+        }
+        if let Ok(value) = env::var(DATA_DIR_VAR) {
+            self.data_dir = value;
+        }
+        if let Ok(value) = env::var(LOG_LEVEL_VAR) {
+            self.log_level = value;
+        }
+        if let Ok(value) = env::var(AUTO_CREATE_TOPICS_VAR) {
+            self.auto_create_topics = AutoCreatePolicy::parse(&value)
+                .ok_or(format!("Invalid {} value '{}', expected never, subscribe or always", AUTO_CREATE_TOPICS_VAR, value))?;
+        }
+        if let Ok(value) = env::var(METRICS_PORT_VAR) {
+            self.set_metrics_port(&value).map_err(|err| format!("Invalid {} value: {}", METRICS_PORT_VAR, err))?;
+        }
+        if let Ok(value) = env::var(FSYNC_VAR) {
+            self.storage.fsync = FsyncPolicy::parse(&value)
+                .ok_or(format!("Invalid {} value '{}', expected never or always", FSYNC_VAR, value))?;
+        }
+        if let Ok(value) = env::var(SNAPSHOT_INTERVAL_VAR) {
+            self.storage.snapshot_interval_ms = value.parse()
+                .map_err(|_| format!("Invalid {} value '{}', expected a number of milliseconds", SNAPSHOT_INTERVAL_VAR, value))?;
+        }
+        if let Ok(value) = env::var(CURVE_PUBLIC_KEY_VAR) {
+            self.curve.public_key = Some(value);
+        }
+        if let Ok(value) = env::var(CURVE_SECRET_KEY_VAR) {
+            self.curve.secret_key = Some(value);
+        }
+        Ok(())
+    }
+
+    fn apply_command_line(&mut self, command_line: &CommandLine) -> Result<(), String> {
+        if !command_line.bind.is_empty() {
+            self.bind = command_line.bind.clone();
+        }
+        if let Some(data_dir) = &command_line.data_dir {
+            self.data_dir = data_dir.clone();
+        }
+        if let Some(log_level) = &command_line.log_level {
+            self.log_level = log_level.clone();
+        }
+        if let Some(metrics_port) = &command_line.metrics_port {
+            self.set_metrics_port(metrics_port).map_err(|err| format!("Invalid --metrics-port value: {}", err))?;
+        }
+        Ok(())
+    }
+
+    fn set_metrics_port(&mut self, value: &str) -> Result<(), String> {
+        if value == "off" {
+            self.metrics.enabled = false;
+            return Ok(());
+        }
+        self.metrics.port = value.parse().map_err(|_| format!("'{}' is not a port number or off", value))?;
+        self.metrics.enabled = true;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.bind.is_empty() {
+            return Err("At least one bind endpoint is required".to_owned());
+        }
+        if let Some(endpoint) = self.bind.iter().find(|endpoint| !endpoint.contains("://")) {
+            return Err(format!("Bind endpoint '{}' is missing its transport, for example tcp://*:5555", endpoint));
+        }
+        if self.data_dir.is_empty() {
+            return Err("The data directory can't be empty".to_owned());
+        }
+        if let Err(err) = EnvFilter::try_new(&self.log_level) {
+            return Err(format!("Invalid log level '{}': {}", self.log_level, err));
+        }
+        if self.retention.durable_session_timeout_secs == 0 || self.retention.ephemeral_session_timeout_secs == 0 {
+            return Err("Session timeouts must be greater than 0".to_owned());
+        }
+        if self.retention.default_ttl_secs.is_some_and(|ttl_secs| ttl_secs == 0 || ttl_secs > MAX_TIMESTAMP) {
+            return Err(format!("The default time-to-live must be between 1 and {} seconds", MAX_TIMESTAMP));
+        }
+        let limits = [self.limits.max_payload_bytes, self.limits.max_topics, self.limits.max_posts_per_topic];
+        if limits.contains(&Some(0)) {
+            return Err("Limits must be greater than 0, leave them out to disable them".to_owned());
+        }
+        if self.limits.max_delay_ms == 0 || self.limits.max_delay_ms > MAX_DELAY_LIMIT_MS {
+            return Err(format!("The maximum delay must be between 1 and {} ms", MAX_DELAY_LIMIT_MS));
+        }
+        if self.retention.visibility_timeout_ms > self.limits.max_delay_ms {
+            return Err("The visibility timeout can't be longer than the maximum delay".to_owned());
+        }
+        if self.auth.required && self.auth.clients.is_empty() {
+            return Err("Authentication is required but no clients are configured".to_owned());
+        }
+        if let Some((client_id, _)) = self.auth.clients.iter().find(|(_, secret)| secret.is_empty()) {
+            return Err(format!("Client '{}' has an empty secret", client_id));
+        }
+        self.curve.keypair()?;
+        self.acl.validate()
+    }
+
+    pub fn metrics_port(&self) -> Option<u16> {
+        if self.metrics.enabled { Some(self.metrics.port) } else { None }
+    }
+
+    // Files of the broker are kept in the data directory
+    pub fn data_path(&self, file_name: &str) -> PathBuf {
+        Path::new(&self.data_dir).join(file_name)
+    }
+
+    pub fn session_timeout(&self, session: SessionType) -> u64 {
+        match session {
+            SessionType::Durable => self.retention.durable_session_timeout_secs,
+            SessionType::Ephemeral => self.retention.ephemeral_session_timeout_secs
+        }
+    }
+
+    // Secrets are redacted
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+}
+
+fn serialize_redacted_secret<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| REDACTED).serialize(serializer)
+}
+
+fn serialize_redacted_secrets<S: Serializer>(secrets: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(secrets.keys().map(|client_id| (client_id, REDACTED)))
+}
+
+// Reloads the authentication and access control sections when the configuration file changes
+pub struct ConfigWatcher {
+    command_line: CommandLine,
+    modified: Option<SystemTime>,
+    last_check: Instant
+}
+
+impl ConfigWatcher {
+    // Created before the broker moves to its data directory, so a relative configuration path still works
+    pub fn new(command_line: &CommandLine) -> ConfigWatcher {
+        let mut command_line = command_line.clone();
+        command_line.config_file = command_line.config_file()
+            .map(|path| fs::canonicalize(&path).map_or(path, |path| path.to_string_lossy().into_owned()));
+        let modified = ConfigWatcher::modified(&command_line);
+        ConfigWatcher { command_line, modified, last_check: Instant::now() }
+    }
+
+    // Returns whether the access settings were reloaded, an invalid file keeps the current ones
+    pub fn reload_access(&mut self, config: &mut Config) -> Result<bool, String> {
+        if self.last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+            return Ok(false);
+        }
+        self.last_check = Instant::now();
+        let modified = ConfigWatcher::modified(&self.command_line);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.modified = modified;
+
+        let reloaded = Config::load(&self.command_line)?;
+        config.auth = reloaded.auth;
+        config.acl = reloaded.acl;
+        Ok(true)
+    }
+
+    fn modified(command_line: &CommandLine) -> Option<SystemTime> {
+        command_line.config_file.as_ref().and_then(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line_options() {
+        let command_line = CommandLine::parse(&arguments(&["--bind", "tcp://*:1", "--bind", "ipc://broker", "--metrics-port", "off", "-h"])).unwrap();
+        assert_eq!(command_line.bind, vec!["tcp://*:1", "ipc://broker"]);
+        assert_eq!(command_line.metrics_port.as_deref(), Some("off"));
+        assert!(command_line.help);
+        assert!(!command_line.print_config);
+
+        assert_eq!(CommandLine::parse(&arguments(&["--data-dir"])).unwrap_err(), "Missing value for --data-dir");
+        assert_eq!(CommandLine::parse(&arguments(&["--verbose"])).unwrap_err(), "Unknown option --verbose");
+    }
+
+    #[test]
+    fn configuration_files_are_parsed_strictly() {
+        let config: Config = toml::from_str(r#"
+            bind = ["tcp://*:6000"]
+            auto_create_topics = "never"
+
+            [storage]
+            fsync = "always"
+
+            [limits]
+            max_topics = 10
+        "#).unwrap();
+        assert_eq!(config.bind, vec!["tcp://*:6000"]);
+        assert_eq!(config.auto_create_topics, AutoCreatePolicy::Never);
+        assert_eq!(config.storage.fsync, FsyncPolicy::Always);
+        assert_eq!(config.limits.max_topics, Some(10));
+        assert_eq!(config.limits.max_delay_ms, DEFAULT_MAX_DELAY_MS);
+        assert!(config.validate().is_ok());
+
+        assert!(toml::from_str::<Config>("bnid = [\"tcp://*:6000\"]").is_err());
+        assert!(toml::from_str::<Config>("[limits]\nmax_topic = 10").is_err());
+    }
+
+    #[test]
+    fn invalid_configurations_are_refused() {
+        let invalid: [fn(&mut Config); 10] = [
+            |config| config.bind.clear(),
+            |config| config.bind = vec!["*:5555".to_owned()],
+            |config| config.data_dir.clear(),
+            |config| config.limits.max_posts_per_topic = Some(0),
+            |config| config.limits.max_delay_ms = 0,
+            |config| config.limits.max_delay_ms = MAX_DELAY_LIMIT_MS + 1,
+            |config| config.retention.visibility_timeout_ms = config.limits.max_delay_ms + 1,
+            |config| config.retention.default_ttl_secs = Some(MAX_TIMESTAMP + 1),
+            |config| config.auth.required = true,
+            |config| config.curve.public_key = Some("public".to_owned())
+        ];
+        for change in invalid {
+            let mut config = Config::default();
+            change(&mut config);
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+
+        let mut config = Config::default();
+        config.limits.max_delay_ms = MAX_DELAY_LIMIT_MS;
+        config.retention.default_ttl_secs = Some(MAX_TIMESTAMP);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut config = Config::default();
+        config.auth.clients.insert("alice".to_owned(), "client secret".to_owned());
+        config.curve.secret_key = Some("curve secret".to_owned());
+        let printed = config.to_toml();
+        assert!(!printed.contains("client secret"));
+        assert!(!printed.contains("curve secret"));
+        assert!(printed.contains(REDACTED));
+    }
+
+    // The only test changing the environment, so the others don't see its variables
+    #[test]
+    fn environment_overrides_the_file_and_the_command_line_overrides_both() {
+        let config_dir = env::temp_dir().join(format!("broker-config-test-{}", std::process::id()));
+        fs::create_dir_all(&config_dir).unwrap();
+        let config_file = config_dir.join("broker.toml");
+        fs::write(&config_file, "data_dir = \"from-file\"\nlog_level = \"debug\"\n").unwrap();
+        let command_line = CommandLine::parse(&arguments(&["--config", config_file.to_str().unwrap(), "--data-dir", "from-command-line"])).unwrap();
+
+        env::set_var(DATA_DIR_VAR, "from-env");
+        env::set_var(BIND_VAR, "tcp://*:1, tcp://*:2");
+        env::set_var(AUTO_CREATE_TOPICS_VAR, "Always");
+        env::set_var(METRICS_PORT_VAR, "off");
+        env::set_var(SNAPSHOT_INTERVAL_VAR, "250");
+        let config = Config::load(&command_line);
+
+        env::set_var(FSYNC_VAR, "sometimes");
+        let invalid = Config::load(&command_line);
+
+        for var in [DATA_DIR_VAR, BIND_VAR, AUTO_CREATE_TOPICS_VAR, METRICS_PORT_VAR, SNAPSHOT_INTERVAL_VAR, FSYNC_VAR] {
+            env::remove_var(var);
+        }
+        fs::remove_dir_all(&config_dir).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.data_dir, "from-command-line");
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.bind, vec!["tcp://*:1", "tcp://*:2"]);
+        assert_eq!(config.auto_create_topics, AutoCreatePolicy::Always);
+        assert_eq!(config.metrics_port(), None);
+        assert_eq!(config.storage.snapshot_interval_ms, 250);
+        assert!(invalid.unwrap_err().contains(FSYNC_VAR));
+    }
+}
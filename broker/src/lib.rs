@@ -0,0 +1,1924 @@
+use meic_mq::messages::get::{ Request as GetRequest, Reply as GetReply, Ack as AckRequest, AckReply, Nack as NackRequest, NackReply };
+use meic_mq::messages::get::{ DEAD_LETTER_REASON_HEADER, DEAD_LETTER_SUB_ID_HEADER, ORIGINAL_TOPIC_HEADER, ORIGINAL_POST_NO_HEADER, DELIVERY_ATTEMPTS_HEADER };
+use meic_mq::messages::put::{ Request as PutRequest, Reply as PutReply };
+use meic_mq::messages::subscribe::{ Request as SubRequest, Reply as SubReply, SessionType };
+use meic_mq::messages::unsubscribe::{ Request as UnsubRequest, Reply as UnsubReply };
+use meic_mq::messages::topic::{ CreateRequest as TopicCreateRequest, CreateReply as TopicCreateReply, DeleteRequest as TopicDeleteRequest, DeleteReply as TopicDeleteReply,
+    ListRequest as TopicListRequest, ListReply as TopicListReply, DescribeRequest as TopicDescribeRequest, DescribeReply as TopicDescribeReply, TopicSettings };
+use meic_mq::messages::admin::{ SubscribersRequest, SubscribersReply, SubscriberInfo, EvictRequest, EvictReply, PurgeRequest, PurgeReply, SnapshotRequest, SnapshotReply };
+use meic_mq::messages::hello::{ Request as HelloRequest, Reply as HelloReply, REQUEST_HEADER as HELLO_HEAD, FEATURE_COMPRESSION, FEATURE_HEADERS };
+use meic_mq::messages::error::{ REQUEST_HEADER as ERROR_HEAD, BrokerErrorMessage, BrokerErrorType };
+use meic_mq::messages::{Message, NetworkTradeable, DeserializationErrors, PostMetadata, ClientRequest, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use meic_mq::auth::Signature;
+use meic_mq::codec::{ self, Codec };
+use meic_mq::checksum;
+use meic_mq::compression::Compression;
+use meic_mq::curve::KeyPair;
+use meic_mq::ffi;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::{ self, Write };
+use std::env;
+use std::process;
+use std::sync::{ Arc, Mutex };
+use std::fs::{ self, File, OpenOptions };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use serde::{ Serialize, Deserialize };
+use tracing::{ debug, error, info, info_span, warn, field, Span };
+use tracing_subscriber::EnvFilter;
+
+use acl::Action;
+use config::{ AuthConfig, CommandLine, Config, ConfigWatcher, FsyncPolicy };
+use metrics::Metrics;
+use replay::ReplayCache;
+pub use shutdown::ShutdownHandle;
+
+mod acl;
+pub mod config;
+mod metrics;
+mod replay;
+pub mod shutdown;
+mod state_file;
+
+const STATE_FILE_PATH: &str = "state.bson";
+const STATE_TEMP_FILE_PATH: &str = "state.bson.tmp";
+const AUDIT_LOG_PATH: &str = "audit.log";
+const SWEEP_INTERVAL_MS: i64 = 1000;
+const DRAIN_POLL_MS: i64 = 100;
+const SOCKET_LINGER_MS: i32 = 1000;
+// Message type reported in the metrics for requests without a readable envelope
+const MALFORMED_MSG_TYPE: &str = "MALFORMED";
+// Protocol features offered to clients in the HELLO exchange
+const SUPPORTED_FEATURES: &[&str] = &[FEATURE_HEADERS, FEATURE_COMPRESSION];
+const DURABLE_SESSION_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+const EPHEMERAL_SESSION_TIMEOUT_SECS: u64 = 60;
+const DEAD_LETTER_TOPIC_SUFFIX: &str = ".dead_letter";
+const DEFAULT_VISIBILITY_TIMEOUT_MS: u64 = 30_000;
+// BSON stores integers as i64, later timestamps would keep the state from being saved
+const MAX_TIMESTAMP: u64 = i64::MAX as u64;
+// Acknowledgements of older versions carry no priority
+const PRIORITY_VERSION: u32 = 2;
+
+pub const USAGE_MESSAGE: &str = "USAGE:\nbroker [options]\nbroker <command> [state file]\noptions:
+  --config <file>          read the configuration from a TOML file, also set by BROKER_CONFIG
+  --bind <endpoint>        endpoint to bind the broker socket to, can be repeated
+  --data-dir <dir>         directory holding the state file and the audit log
+  --log-level <level>      log level used when RUST_LOG is not set
+  --metrics-port <port>    port serving the metrics, or off
+  --print-config           print the effective configuration and exit
+command: one of
+  dump        print the topics, subscribers and deduplication set of a state file
+  export      print a state file as JSON
+  validate    check the invariants of a state file
+  repair      fix the invariants of a state file and upgrade it to the current format, keeping a .bak copy
+  keygen      print a new CURVE key pair, for the broker or for a client
+The state file defaults to state.bson.";
+
+#[derive(Debug, Serialize, Deserialize)]
+enum SubscriberStatus {
+    WaitingAck,
+    WaitingGet
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SubscriberData {
+    topic: String,
+    // Client that subscribed, the only one allowed to use the subscription, None if it was unsigned
+    #[serde(default)]
+    client_id: Option<String>,
+    status: SubscriberStatus,
+    // Last read post number for each priority
+    last_read_posts: HashMap<String, u64>,
+    #[serde(default)]
+    delivered_priority: u8,
+    #[serde(default)]
+    delivered_post: u64,
+    #[serde(default)]
+    delivery_attempts: u32,
+    #[serde(default)]
+    max_delivery_attempts: Option<u32>,
+    #[serde(default)]
+    dead_letter_topic: Option<String>,
+    #[serde(default = "default_visibility_timeout")]
+    visibility_timeout_ms: u64,
+    // Until then the delivered or rejected post is not delivered again
+    #[serde(default)]
+    redeliver_at_ms: u64,
+    // Posts of each priority dead-lettered on a get, reported as skipped until the subscriber reads past them
+    #[serde(default)]
+    unreported_skips: HashMap<String, u64>,
+    #[serde(default)]
+    session: SessionType,
+    #[serde(default = "default_session_timeout")]
+    session_timeout: u64,
+    #[serde(default = "now")]
+    last_seen: u64
+}
+
+impl SubscriberData {
+    fn new(topic: String, last_read_posts: HashMap<String, u64>, session: SessionType, session_timeout: u64,
+            max_delivery_attempts: Option<u32>, dead_letter_topic: Option<String>, visibility_timeout_ms: u64) -> SubscriberData {
+        SubscriberData {
+            topic,
+            client_id: None,
+            status: SubscriberStatus::WaitingGet,
+            last_read_posts,
+            delivered_priority: 0,
+            delivered_post: 0,
+            delivery_attempts: 0,
+            max_delivery_attempts,
+            dead_letter_topic,
+            visibility_timeout_ms,
+            redeliver_at_ms: 0,
+            unreported_skips: HashMap::new(),
+            session,
+            session_timeout,
+            last_seen: now()
+        }
+    }
+
+    fn touch(&mut self) {
+        self.last_seen = now();
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_seen) > self.session_timeout
+    }
+
+    fn last_read_post(&self, priority: u8) -> u64 {
+        *self.last_read_posts.get(&priority.to_string()).unwrap_or(&0)
+    }
+
+    fn mark_read(&mut self, priority: u8, post_no: u64) {
+        self.last_read_posts.insert(priority.to_string(), post_no);
+    }
+
+    fn mark_delivered(&mut self, priority: u8, post_no: u64) {
+        self.redeliver_at_ms = now_ms().saturating_add(self.visibility_timeout_ms);
+        if self.delivered_priority == priority && self.delivered_post == post_no {
+            self.delivery_attempts += 1;
+        } else {
+            self.delivered_priority = priority;
+            self.delivered_post = post_no;
+            self.delivery_attempts = 1;
+        }
+    }
+
+    fn was_delivered(&self, priority: u8, post_no: u64) -> bool {
+        self.delivered_priority == priority && self.delivered_post == post_no
+    }
+
+    fn unreported_skips(&self, priority: u8) -> u64 {
+        *self.unreported_skips.get(&priority.to_string()).unwrap_or(&0)
+    }
+
+    fn add_unreported_skip(&mut self, priority: u8) {
+        *self.unreported_skips.entry(priority.to_string()).or_insert(0) += 1;
+    }
+
+    fn clear_unreported_skips(&mut self, priority: u8) {
+        self.unreported_skips.remove(&priority.to_string());
+    }
+
+    fn deliveries_exhausted(&self) -> bool {
+        matches!(self.max_delivery_attempts, Some(max_delivery_attempts) if self.delivery_attempts >= max_delivery_attempts)
+    }
+
+    fn dead_letter_topic(&self) -> String {
+        resolve_dead_letter_topic(&self.topic, self.dead_letter_topic.as_ref())
+    }
+
+    fn change_status(&mut self, status: SubscriberStatus) {
+        self.status = status;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Post {
+    #[serde(serialize_with = "state_file::serialize_payload", deserialize_with = "state_file::deserialize_payload")]
+    payload: Vec<u8>,
+    expires_at: Option<u64>,
+    priority: u8,
+    headers: HashMap<String, String>,
+    metadata: PostMetadata,
+    // Payloads compressed by their publisher are stored and delivered as they are
+    #[serde(default)]
+    compression: Option<Compression>,
+    // Verified when the state is recovered, posts saved by older brokers have none
+    #[serde(default)]
+    checksum: Option<u32>
+}
+
+impl Post {
+    fn new(payload: Vec<u8>, expires_at: Option<u64>, priority: u8, headers: HashMap<String, String>, pub_id: String) -> Post {
+        let metadata = PostMetadata {
+            pub_id,
+            received_at_ms: now_ms(),
+            post_no: 0,
+            correlation_id: String::new()
+        };
+        let checksum = Some(checksum::compute(&payload));
+        Post { payload, expires_at, priority, headers, metadata, compression: None, checksum }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScheduledPost {
+    deliver_at_ms: u64,
+    post: Post
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PriorityBand {
+    posts: HashMap<String, Post>,
+    post_counter: u64
+}
+
+impl PriorityBand {
+    fn increment_counter(&mut self) -> u64 {
+        self.post_counter += 1;
+        self.post_counter
+    }
+
+    // First post after the last read one that is still deliverable and how many were skipped to reach it
+    fn next_post(&self, last_read_post: u64, now: u64) -> Option<(u64, u64, &Post)> {
+        let mut skipped: u64 = 0;
+        for post_no in (last_read_post + 1)..=self.post_counter {
+            match self.posts.get(&post_no.to_string()) {
+                Some(post) if !post.is_expired(now) => return Some((post_no, skipped, post)),
+                _ => skipped += 1
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TopicData {
+    #[serde(default)]
+    settings: TopicSettings,
+    // Each priority has its own post numbering, indexed by priority
+    bands: HashMap<String, PriorityBand>,
+    // Posts only numbered and delivered once their delivery time is due
+    #[serde(default)]
+    scheduled: Vec<ScheduledPost>,
+    // Dead-letter topics keep their posts for the subscribers that come later
+    #[serde(default)]
+    dead_letter: bool
+}
+
+impl TopicData {
+    fn new(settings: TopicSettings) -> TopicData {
+        TopicData { settings, bands: HashMap::new(), scheduled: Vec::new(), dead_letter: false }
+    }
+
+    fn add_post(&mut self, mut post: Post) -> u64 {
+        let band = self.bands.entry(post.priority.to_string()).or_default();
+        let post_no = band.increment_counter();
+        post.metadata.post_no = post_no;
+        band.posts.insert(post_no.to_string(), post);
+        post_no
+    }
+
+    fn get_post(&self, priority: u8, post_no: u64) -> Option<&Post> {
+        self.bands.get(&priority.to_string()).and_then(|band| band.posts.get(&post_no.to_string()))
+    }
+
+    fn post_counters(&self) -> HashMap<String, u64> {
+        self.bands.iter().map(|(priority, band)| (priority.clone(), band.post_counter)).collect()
+    }
+
+    // Last read post of each priority for a new subscriber, which starts after the posts already published
+    // except on dead-letter topics, where it starts at the earliest retained post
+    fn initial_read_posts(&self) -> HashMap<String, u64> {
+        self.bands.iter().map(|(priority, band)| {
+            let first_retained = band.posts.keys().filter_map(|post_no| post_no.parse::<u64>().ok()).min().filter(|_| self.dead_letter);
+            (priority.clone(), first_retained.map_or(band.post_counter, |post_no| post_no - 1))
+        }).collect()
+    }
+
+    // Posts held for the topic, scheduled ones included
+    fn retained_posts(&self) -> u64 {
+        self.scheduled.len() as u64 + self.bands.values().map(|band| band.posts.len() as u64).sum::<u64>()
+    }
+
+    // Posts published to the topic that a subscriber has not read yet
+    fn lag(&self, subscriber_data: &SubscriberData) -> u64 {
+        self.bands.iter()
+            .map(|(priority, band)| band.post_counter.saturating_sub(*subscriber_data.last_read_posts.get(priority).unwrap_or(&0)))
+            .sum()
+    }
+
+    fn post_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.bands.iter()
+            .flat_map(|(priority, band)| band.posts.keys().map(move |post_no| format!("{}:{}", priority, post_no)))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Next post to deliver to a subscriber, taking the highest priority band with pending posts
+    fn next_post(&self, subscriber_data: &SubscriberData, now: u64) -> Option<(u8, u64, u64, &Post)> {
+        let mut priorities: Vec<u8> = self.bands.keys().filter_map(|priority| priority.parse().ok()).collect();
+        priorities.sort_unstable_by(|a, b| b.cmp(a));
+        priorities.into_iter().find_map(|priority| {
+            self.bands[&priority.to_string()].next_post(subscriber_data.last_read_post(priority), now)
+                .map(|(post_no, skipped, post)| (priority, post_no, skipped, post))
+        })
+    }
+
+    fn schedule_post(&mut self, post: Post, deliver_at_ms: u64) {
+        self.scheduled.push(ScheduledPost { deliver_at_ms, post });
+    }
+
+    // Move the scheduled posts that are due into the topic sequence, in delivery time order
+    fn release_scheduled_posts(&mut self, now_ms: u64) -> usize {
+        let (mut due, pending): (Vec<ScheduledPost>, Vec<ScheduledPost>) = self.scheduled.drain(..)
+            .partition(|scheduled_post| scheduled_post.deliver_at_ms <= now_ms);
+        self.scheduled = pending;
+        due.sort_by_key(|scheduled_post| scheduled_post.deliver_at_ms);
+        let released = due.len();
+        for scheduled_post in due {
+            self.add_post(scheduled_post.post);
+        }
+        released
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BrokerState {
+    broker_uuid: String,
+    subs: HashMap<String, SubscriberData>,
+    topics: HashMap<String, TopicData>,
+    received_uuids: HashSet<String>,
+}
+
+impl BrokerState {
+    pub fn new() -> BrokerState {
+        BrokerState {
+            broker_uuid: Uuid::new_v4().to_string(),
+            subs: HashMap::new(),
+            topics: HashMap::new(),
+            received_uuids: HashSet::new()
+        }
+    }
+}
+
+// Entry point of the broker binary
+pub fn main() {
+    // Offline commands on a state file
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    if arguments.first().is_some_and(|argument| argument == "keygen") {
+        process::exit(print_keypair());
+    }
+    if arguments.first().is_some_and(|argument| !argument.starts_with('-')) {
+        process::exit(state_file::run_command(&arguments));
+    }
+
+    let command_line = match CommandLine::parse(&arguments) {
+        Ok(command_line) => command_line,
+        Err(err) => {
+            println!("Incorrect usage of the command line interface: {}.", err);
+            println!("{}", USAGE_MESSAGE);
+            process::exit(1);
+        }
+    };
+    if command_line.help {
+        println!("{}", USAGE_MESSAGE);
+        return;
+    }
+    let config = match Config::load(&command_line) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
+    };
+    if command_line.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level)))
+        .init();
+
+    let config_watcher = ConfigWatcher::new(&command_line);
+    let shutdown = ShutdownHandle::new();
+    if let Err(err) = shutdown.listen_for_signals() {
+        error!(%err, "Couldn't listen for shutdown signals");
+        process::exit(1);
+    }
+    process::exit(serve(config, Some(config_watcher), &shutdown));
+}
+
+// Runs the broker in this process until the handle asks it to stop and returns the exit status.
+// Signals are only listened for if the caller asks the handle to, and the access settings are only reloaded with a watcher
+pub fn serve(config: Config, config_watcher: Option<ConfigWatcher>, shutdown: &ShutdownHandle) -> i32 {
+    // The state file and the audit log are kept in the data directory
+    if let Err(err) = fs::create_dir_all(&config.data_dir) {
+        error!(data_dir = %config.data_dir, %err, "Couldn't use the data directory");
+        return 1;
+    }
+    run(config, config_watcher, shutdown)
+}
+
+// Prints the keys in the format of the configuration file
+fn print_keypair() -> i32 {
+    match KeyPair::generate() {
+        Ok(keypair) => {
+            println!("public_key = \"{}\"", keypair.public_key);
+            println!("secret_key = \"{}\"", keypair.secret_key());
+            0
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+// Serves requests until a shutdown is requested and returns the exit status
+fn run(mut config: Config, mut config_watcher: Option<ConfigWatcher>, shutdown: &ShutdownHandle) -> i32 {
+    let context = zmq::Context::new();
+    let mut socket = context.socket(zmq::REP).unwrap();
+    // Replies still queued when the broker stops are dropped after a while instead of blocking the exit
+    socket.set_linger(SOCKET_LINGER_MS).unwrap();
+
+    // Set before binding, the endpoints would accept plaintext connections otherwise. Embedders may pass a configuration that was never validated
+    match config.curve.keypair() {
+        Ok(Some(keypair)) => {
+            if let Err(err) = keypair.configure_server(&socket) {
+                error!(%err, "Couldn't enable CURVE encryption");
+                return 1;
+            }
+            info!(public_key = %keypair.public_key, "Encrypting connections with CURVE");
+        },
+        Ok(None) => {},
+        Err(err) => {
+            error!(%err, "Invalid CURVE keys");
+            return 1;
+        }
+    }
+
+    let mut endpoints = Vec::new();
+    for endpoint in config.bind.iter() {
+        if let Err(err) = socket.bind(endpoint) {
+            error!(endpoint, %err, "Couldn't bind the broker socket");
+            return 1;
+        }
+        info!(endpoint, "Listening for requests");
+        // Wildcard endpoints can only be unbound with the address they resolved to
+        endpoints.push(socket.get_last_endpoint().ok().and_then(Result::ok).unwrap_or(endpoint.clone()));
+    }
+
+    let mut state = match recover_state(&config) {
+        Ok(state) => state,
+        Err(err) => {
+            error!("{}", err);
+            return 1;
+        }
+    };
+    let mut replay_cache = ReplayCache::default();
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let metrics_server = config.metrics_port().and_then(|port| metrics::serve(port, metrics.clone(), shutdown.clone()));
+
+    let snapshot_interval = Duration::from_millis(config.storage.snapshot_interval_ms);
+    let mut last_snapshot = Instant::now();
+    let mut last_sweep = Instant::now();
+    let mut unsaved_changes = false;
+    while !shutdown.is_requested() {
+        let mut state_changed = false;
+        let has_request = wait_for_request(&socket, SWEEP_INTERVAL_MS, shutdown);
+        if release_scheduled_posts(&mut state) {
+            state_changed = true;
+        }
+        if has_request {
+            handle_requests(&socket, &mut state, &config, &metrics, &mut replay_cache);
+            state_changed = true;
+        }
+        if let Some(config_watcher) = config_watcher.as_mut() {
+            match config_watcher.reload_access(&mut config) {
+                Ok(true) => info!(acl_rules = config.acl.rules.len(), clients = config.auth.clients.len(), "Reloaded the access settings"),
+                Ok(false) => {},
+                Err(err) => error!(%err, "Couldn't reload the access settings, keeping the current ones")
+            }
+        }
+        replay_cache.prune(now_ms(), config.auth.max_clock_skew_ms);
+        // Both walk the whole state, so they are run once per sweep interval however busy the broker is
+        if last_sweep.elapsed() >= Duration::from_millis(SWEEP_INTERVAL_MS as u64) {
+            if evict_expired_subscribers(&mut state, &config) {
+                state_changed = true;
+            }
+            if remove_expired_posts(&mut state) {
+                state_changed = true;
+            }
+            last_sweep = Instant::now();
+        }
+        if state_changed {
+            unsaved_changes = true;
+        }
+        // The gauges walk every post, so they are only taken when the metrics are scraped
+        if metrics.lock().unwrap().state_requested() {
+            metrics.lock().unwrap().observe_state(&state);
+        }
+        if unsaved_changes && last_snapshot.elapsed() >= snapshot_interval {
+            let start = Instant::now();
+            if let Err(err) = save_state(&state, &config) {
+                error!(%err, "Couldn't save the broker state");
+            }
+            metrics.lock().unwrap().observe_snapshot(start.elapsed());
+            last_snapshot = Instant::now();
+            unsaved_changes = false;
+        }
+    }
+
+    // Stop accepting requests first, then answer the ones already received so their clients are not left without a reply
+    info!("Shutting down, answering the requests already received");
+    if let Some(metrics_server) = metrics_server {
+        let _ = metrics_server.join();
+    }
+    for endpoint in endpoints.iter() {
+        if let Err(err) = ffi::unbind(&mut socket, endpoint) {
+            warn!(endpoint, %err, "Couldn't unbind the broker socket");
+        }
+    }
+    let drain_deadline = Instant::now() + Duration::from_millis(config.shutdown_timeout_ms);
+    let mut drained_requests = 0;
+    while Instant::now() < drain_deadline && wait_for_request(&socket, DRAIN_POLL_MS, shutdown) {
+        handle_requests(&socket, &mut state, &config, &metrics, &mut replay_cache);
+        drained_requests += 1;
+    }
+    // The last replies get the rest of the shutdown timeout to be sent
+    let flush_ms = drain_deadline.saturating_duration_since(Instant::now()).as_millis();
+    socket.set_linger(flush_ms.min(i32::MAX as u128) as i32).unwrap();
+    drop(socket);
+
+    match save_state(&state, &config) {
+        Ok(()) => {
+            info!(drained_requests, "Saved the final state snapshot, broker stopped");
+            0
+        },
+        Err(err) => {
+            error!(%err, "Couldn't save the final state snapshot");
+            1
+        }
+    }
+}
+
+// Signals interrupt the poll, which is then treated as a timeout
+fn wait_for_request(socket: &zmq::Socket, timeout_ms: i64, shutdown: &ShutdownHandle) -> bool {
+    match socket.poll(zmq::POLLIN, timeout_ms) {
+        Ok(events) => events > 0,
+        Err(zmq::Error::EINTR) => false,
+        Err(err) => {
+            error!(%err, "Couldn't poll the broker socket");
+            shutdown.shutdown();
+            false
+        }
+    }
+}
+
+// Dead-letter topic chosen by a subscription, or the default one for its topic
+fn resolve_dead_letter_topic(topic: &str, dead_letter_topic: Option<&String>) -> String {
+    match dead_letter_topic {
+        Some(dead_letter_topic) => dead_letter_topic.clone(),
+        None => format!("{}{}", topic, DEAD_LETTER_TOPIC_SUFFIX)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn default_session_timeout() -> u64 {
+    DURABLE_SESSION_TIMEOUT_SECS
+}
+
+fn default_visibility_timeout() -> u64 {
+    DEFAULT_VISIBILITY_TIMEOUT_MS
+}
+
+fn audit(config: &Config, event: &str) {
+    let audit_file = OpenOptions::new().create(true).append(true).open(config.data_path(AUDIT_LOG_PATH));
+    let result = audit_file.and_then(|mut file| writeln!(file, "{} {}", now(), event));
+    if let Err(err) = result {
+        error!(%err, "Couldn't write to audit log");
+    }
+}
+
+// Written to a temporary file first so an interrupted save never leaves a truncated state file
+fn save_state(state: &BrokerState, config: &Config) -> io::Result<()> {
+    let temp_path = config.data_path(STATE_TEMP_FILE_PATH);
+    let mut state_file: File = File::create(&temp_path)?;
+    let state_bytes = bson::to_vec(state).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    state_file.write_all(state_bytes.as_slice())?;
+    if config.storage.fsync == FsyncPolicy::Always {
+        state_file.sync_all()?;
+    }
+    fs::rename(temp_path, config.data_path(STATE_FILE_PATH))
+}
+
+fn recover_state(config: &Config) -> Result<BrokerState, String> {
+    let path = config.data_path(STATE_FILE_PATH);
+    if !path.exists() {
+        return Ok(BrokerState::new());
+    }
+    match state_file::load_state(&path.to_string_lossy()) {
+        Ok(mut state) => {
+            // Corrupted posts are dropped, subscribers skip them like expired ones
+            for post in state_file::remove_corrupted_posts(&mut state) {
+                error!("Dropped corrupted post {}", post);
+                audit(config, &format!("CORRUPTED_POST {}", post));
+            }
+            Ok(state)
+        },
+        Err(err) => Err(format!("Couldn't recover state from {}: {}, inspect it with the validate and repair commands", path.display(), err))
+    }
+}
+
+fn handle_hello(state: &BrokerState, request: HelloRequest) -> Message {
+    let version = request.max_version.min(PROTOCOL_VERSION);
+    if version < request.min_version.max(MIN_PROTOCOL_VERSION) {
+        info!(min_version = request.min_version, max_version = request.max_version, "No protocol version in common");
+        return BrokerErrorMessage::new(BrokerErrorType::UnsupportedVersion, state.broker_uuid.clone()).as_message();
+    }
+    let features: Vec<String> = request.features.into_iter().filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str())).collect();
+    let codecs: Vec<String> = codec::codecs().iter().map(|codec| codec.name().to_owned()).collect();
+    debug!(version, ?features, ?codecs, "Negotiated protocol");
+
+    HelloReply::new(state.broker_uuid.clone(), version, features, codecs).as_message()
+}
+
+fn handle_get(state: &mut BrokerState, request: GetRequest, config: &Config) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str()).record("topic", request.topic.as_str());
+    let post_payload: Vec<u8>;
+    let post_compression: Option<Compression>;
+    let post_checksum: Option<u32>;
+    let post_headers: HashMap<String, String>;
+    let post_metadata: PostMetadata;
+    let delivery_attempt: u32;
+    let priority: u8;
+    let post_no: u64;
+    let skipped: u64;
+
+    // Subscriber does not exist
+    if !state.subs.contains_key(&request.sub_id) {
+        return BrokerErrorMessage::new(BrokerErrorType::SubscriberNotRegistered, state.broker_uuid.clone()).as_message();
+    }
+
+    {
+        // Subscriber is not subbed to that topic
+        let subscriber_data = state.subs.get_mut(&request.sub_id).unwrap();
+        subscriber_data.touch();
+        if subscriber_data.topic != request.topic {
+            return BrokerErrorMessage::new(BrokerErrorType::TopicMismatch, state.broker_uuid.clone()).as_message();
+        }
+
+        // The topic does not exist
+        if !state.topics.contains_key(&request.topic) {
+            return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message();
+        }
+
+        // Delivered post is still within its visibility timeout or requeue delay
+        if now_ms() < subscriber_data.redeliver_at_ms {
+            let error_type = match subscriber_data.status {
+                SubscriberStatus::WaitingAck => BrokerErrorType::AwaitingAck,
+                SubscriberStatus::WaitingGet => BrokerErrorType::RedeliveryDelayed
+            };
+            return BrokerErrorMessage::new(error_type, state.broker_uuid.clone()).as_message();
+        }
+
+        // Post was delivered too many times without being acknowledged
+        if let SubscriberStatus::WaitingAck = subscriber_data.status {
+            if subscriber_data.deliveries_exhausted() {
+                let dead_letter_priority = subscriber_data.delivered_priority;
+                dead_letter_post(state, config, &request.sub_id, "Delivery attempts exhausted without acknowledgement");
+                // The subscriber still expects that post, it learns to skip it with the next post of the same priority
+                state.subs.get_mut(&request.sub_id).unwrap().add_unreported_skip(dead_letter_priority);
+            }
+        }
+    }
+
+    {
+        let subscriber_data = state.subs.get_mut(&request.sub_id).unwrap();
+
+        // Skip the posts that expired or were already reclaimed by the sweeper
+        let topic_data = state.topics.get(&request.topic).unwrap();
+        match topic_data.next_post(subscriber_data, now()) {
+            Some((next_priority, next_post_no, next_skipped, post)) => {
+                priority = next_priority;
+                post_no = next_post_no;
+                skipped = next_skipped + subscriber_data.unreported_skips(next_priority);
+                post_payload = post.payload.clone();
+                post_compression = post.compression;
+                post_checksum = post.checksum;
+                post_headers = post.headers.clone();
+                post_metadata = post.metadata.clone();
+            },
+            // There are not posts in that topic for this reader
+            None => return BrokerErrorMessage::new(BrokerErrorType::NoPostsInTopic, state.broker_uuid.clone()).as_message()
+        }
+    
+        // Change status to waiting for ACK
+        subscriber_data.change_status(SubscriberStatus::WaitingAck);
+        subscriber_data.mark_delivered(priority, post_no);
+        delivery_attempt = subscriber_data.delivery_attempts;
+    }
+    Span::current().record("post_no", post_no);
+    debug!(priority, skipped, delivery_attempt, publish_correlation_id = %post_metadata.correlation_id, "Delivering post");
+
+    let mut reply = GetReply::new(
+        request.sub_id.clone(), 
+        post_no, 
+        state.broker_uuid.clone(),
+        post_payload
+    );
+    reply.priority = priority;
+    reply.skipped = skipped;
+    reply.headers = post_headers;
+    reply.metadata = post_metadata;
+    reply.delivery_attempt = delivery_attempt;
+    reply.compression = post_compression;
+    reply.checksum = post_checksum;
+    if request.payload_frame {
+        let payload = std::mem::take(&mut reply.payload);
+        return reply.as_message().with_payload_frame(payload);
+    }
+    reply.as_message()
+}
+
+fn handle_put(state: &mut BrokerState, request: PutRequest, config: &Config, correlation_id: String) -> Message {
+    Span::current().record("pub_id", request.pub_id.as_str())
+        .record("topic", request.topic.as_str())
+        .record("message_uuid", request.message_uuid.as_str());
+
+    // Repeated message
+    if state.received_uuids.contains(&request.message_uuid) {
+        return BrokerErrorMessage::new(BrokerErrorType::DuplicateMessage, state.broker_uuid.clone()).as_message();
+    }
+
+    // Payload corrupted on the way
+    if !checksum::verify(&request.payload, request.checksum) {
+        warn!("Payload doesn't match its checksum");
+        return BrokerErrorMessage::new(BrokerErrorType::ChecksumMismatch, state.broker_uuid.clone()).as_message();
+    }
+
+    // Payload too large
+    if config.limits.max_payload_bytes.is_some_and(|max_payload_bytes| request.payload.len() as u64 > max_payload_bytes) {
+        return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+    }
+
+    // Expiry out of range
+    if request.expires_at.is_some_and(|expires_at| expires_at > MAX_TIMESTAMP) {
+        return BrokerErrorMessage::malformed(state.broker_uuid.clone(), "the expiry time is out of range").as_message();
+    }
+
+    // Delivery time out of range
+    if request.deliver_at_ms.is_some_and(|deliver_at_ms| deliver_at_ms > MAX_TIMESTAMP) {
+        return BrokerErrorMessage::malformed(state.broker_uuid.clone(), "the delivery time is out of range").as_message();
+    }
+
+    // Inexistant topic
+    if !state.topics.contains_key(&request.topic)  {
+        if !config.auto_create_topics.on_publish() {
+            return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message();
+        }
+        if topic_limit_reached(state, config) {
+            return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+        }
+        create_topic(state, config, &request.topic, TopicSettings::default());
+    }
+    let topic_data = state.topics.get_mut(&request.topic).unwrap();
+
+    // Topic is full
+    if config.limits.max_posts_per_topic.is_some_and(|max_posts| topic_data.retained_posts() >= max_posts) {
+        return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+    }
+
+    let default_ttl_secs = topic_data.settings.default_ttl_secs.or(config.retention.default_ttl_secs);
+    let expires_at = request.expires_at.or_else(|| default_ttl_secs.map(|ttl_secs| now().saturating_add(ttl_secs).min(MAX_TIMESTAMP)));
+    let mut post = Post::new(request.payload, expires_at, request.priority, request.headers, request.pub_id.clone());
+    post.metadata.correlation_id = correlation_id;
+    post.compression = request.compression;
+    match request.deliver_at_ms {
+        Some(deliver_at_ms) if deliver_at_ms > now_ms() => {
+            topic_data.schedule_post(post, deliver_at_ms);
+            debug!(deliver_at_ms, "Scheduled post");
+        },
+        _ => {
+            let post_no = topic_data.add_post(post);
+            Span::current().record("post_no", post_no);
+        }
+    }
+    state.received_uuids.insert(request.message_uuid.clone());
+    debug!(posts = ?state.topics.get(&request.topic).unwrap().post_keys(), "Stored post");
+
+    PutReply::new(request.message_uuid.clone(), request.topic.clone(), state.broker_uuid.clone()).as_message()
+}
+
+fn handle_sub(state: &mut BrokerState, request: SubRequest, config: &Config, client_id: Option<&str>) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str()).record("topic", request.topic.as_str());
+    // Subscriber already subscribed
+    if state.subs.contains_key(&request.sub_id) {
+        return BrokerErrorMessage::new(BrokerErrorType::SubscriberAlreadyRegistered, state.broker_uuid.clone()).as_message();
+    } 
+
+    // Inexistant topic
+    if !state.topics.contains_key(&request.topic) {
+        if !config.auto_create_topics.on_subscribe() {
+            return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message();
+        }
+        if topic_limit_reached(state, config) {
+            return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+        }
+        create_topic(state, config, &request.topic, TopicSettings::default());
+    }
+
+    // Visibility timeout too long
+    if request.visibility_timeout_ms.is_some_and(|visibility_timeout_ms| visibility_timeout_ms > config.limits.max_delay_ms) {
+        return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+    }
+
+    let session_timeout = request.session_timeout.unwrap_or(config.session_timeout(request.session));
+    let topic_data = state.topics.get(&request.topic).unwrap();
+    let subs_last_read_posts = topic_data.initial_read_posts();
+    let max_delivery_attempts = request.max_delivery_attempts.or(topic_data.settings.max_delivery_attempts);
+    let post_offsets = subs_last_read_posts.iter().map(|(priority, post_no)| (priority.clone(), post_no + 1)).collect();
+    let mut subscriber_data = SubscriberData::new(request.topic.clone(), subs_last_read_posts, request.session, session_timeout,
+        max_delivery_attempts, request.dead_letter_topic.clone(), request.visibility_timeout_ms.unwrap_or(config.retention.visibility_timeout_ms));
+    subscriber_data.client_id = client_id.map(str::to_owned);
+    state.subs.insert(request.sub_id.clone(), subscriber_data);
+
+    info!(session = ?request.session, session_timeout, "Subscribed");
+    debug!(subs = ?state.subs.keys(), "Known subscribers");
+
+    SubReply::new(request.sub_id.clone(), request.topic.clone(), state.broker_uuid.clone(), post_offsets, session_timeout).as_message()
+}
+
+fn handle_unsub(state: &mut BrokerState, request: UnsubRequest) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str());
+    // Subscriber not subscribed
+    if !state.subs.contains_key(&request.sub_id) {
+        return BrokerErrorMessage::new(BrokerErrorType::SubscriberNotRegistered, state.broker_uuid.clone()).as_message();
+    }
+    state.subs.remove(&request.sub_id);
+    
+    info!("Unsubscribed");
+    debug!(subs = ?state.subs.keys(), "Known subscribers");
+
+    UnsubReply::new(request.sub_id.clone(), state.broker_uuid.clone()).as_message()
+}
+
+fn handle_get_ack(state: &mut BrokerState, request: AckRequest) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str()).record("post_no", request.message_no);
+
+    // Subscriber does not exist
+    let subscriber_data: &mut SubscriberData = match state.subs.get_mut(&request.sub_id) {
+        Some(val) => val,
+        None => return BrokerErrorMessage::new(BrokerErrorType::SubscriberNotRegistered, state.broker_uuid.clone()).as_message()
+    };
+    subscriber_data.touch();
+
+    // Not expecting Ack
+    match subscriber_data.status {
+        SubscriberStatus::WaitingGet => return BrokerErrorMessage::new(BrokerErrorType::NotExpectingAck, state.broker_uuid.clone()).as_message(),
+        SubscriberStatus::WaitingAck => subscriber_data.change_status(SubscriberStatus::WaitingGet)
+    }
+
+    // Check message number
+    if !subscriber_data.was_delivered(request.priority, request.message_no) {
+        return BrokerErrorMessage::new(BrokerErrorType::AckMessageMismatch, state.broker_uuid.clone()).as_message();
+    }
+    subscriber_data.mark_read(request.priority, request.message_no);
+    subscriber_data.clear_unreported_skips(request.priority);
+    subscriber_data.redeliver_at_ms = 0;
+
+    let sub_topic: String = subscriber_data.topic.clone();
+
+    remove_read_posts(state, &sub_topic);
+    Span::current().record("topic", sub_topic.as_str());
+    debug!(posts = ?state.topics.get(&sub_topic).map(TopicData::post_keys), "Acknowledged post");
+
+    AckReply::new(request.sub_id.clone(), request.message_no).as_message()
+}
+
+fn handle_get_nack(state: &mut BrokerState, request: NackRequest, config: &Config) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str()).record("post_no", request.message_no);
+
+    // Subscriber does not exist
+    let subscriber_data: &mut SubscriberData = match state.subs.get_mut(&request.sub_id) {
+        Some(val) => val,
+        None => return BrokerErrorMessage::new(BrokerErrorType::SubscriberNotRegistered, state.broker_uuid.clone()).as_message()
+    };
+    subscriber_data.touch();
+
+    // Not expecting Nack
+    if let SubscriberStatus::WaitingGet = subscriber_data.status {
+        return BrokerErrorMessage::new(BrokerErrorType::NotExpectingAck, state.broker_uuid.clone()).as_message();
+    }
+
+    // Check message number
+    if !subscriber_data.was_delivered(request.priority, request.message_no) {
+        return BrokerErrorMessage::new(BrokerErrorType::AckMessageMismatch, state.broker_uuid.clone()).as_message();
+    }
+
+    // Requeue delay too long
+    if request.requeue_delay_ms.is_some_and(|requeue_delay_ms| requeue_delay_ms > config.limits.max_delay_ms) {
+        return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+    }
+
+    // The post is either redelivered on the next get or moved to the dead-letter topic
+    let dead_lettered = subscriber_data.deliveries_exhausted();
+    info!(reason = %request.reason, dead_lettered, "Rejected post");
+    if dead_lettered {
+        // The subscriber skips the posts reported with this one itself
+        subscriber_data.clear_unreported_skips(request.priority);
+        dead_letter_post(state, config, &request.sub_id, &request.reason);
+    } else {
+        subscriber_data.change_status(SubscriberStatus::WaitingGet);
+        subscriber_data.redeliver_at_ms = now_ms().saturating_add(request.requeue_delay_ms.unwrap_or(0));
+    }
+
+    NackReply::new(request.sub_id.clone(), request.message_no, dead_lettered).as_message()
+}
+
+fn handle_topic_create(state: &mut BrokerState, request: TopicCreateRequest, config: &Config) -> Message {
+    Span::current().record("topic", request.topic.as_str());
+    // Topic already exists
+    if state.topics.contains_key(&request.topic) {
+        return BrokerErrorMessage::new(BrokerErrorType::TopicAlreadyExists, state.broker_uuid.clone()).as_message();
+    }
+    if topic_limit_reached(state, config) {
+        return BrokerErrorMessage::new(BrokerErrorType::LimitExceeded, state.broker_uuid.clone()).as_message();
+    }
+    // Time-to-live out of range
+    if request.settings.default_ttl_secs.is_some_and(|ttl_secs| ttl_secs > MAX_TIMESTAMP) {
+        return BrokerErrorMessage::malformed(state.broker_uuid.clone(), "the default time-to-live is out of range").as_message();
+    }
+    create_topic(state, config, &request.topic, request.settings);
+
+    TopicCreateReply::new(request.topic.clone(), state.broker_uuid.clone()).as_message()
+}
+
+fn handle_topic_delete(state: &mut BrokerState, request: TopicDeleteRequest, config: &Config) -> Message {
+    Span::current().record("topic", request.topic.as_str());
+    // Inexistant topic
+    if state.topics.remove(&request.topic).is_none() {
+        return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message();
+    }
+
+    // Its subscribers would otherwise be left without a topic
+    let removed_subscribers: Vec<String> = state.subs.iter()
+        .filter(|(_, sub_data)| sub_data.topic == request.topic)
+        .map(|(sub_id, _)| sub_id.clone())
+        .collect();
+    state.subs.retain(|_, sub_data| sub_data.topic != request.topic);
+    audit(config, &format!("DELETE_TOPIC topic={} removed_subscribers={:?}", request.topic, removed_subscribers));
+    info!(?removed_subscribers, "Deleted topic and its subscribers");
+
+    TopicDeleteReply::new(request.topic.clone(), state.broker_uuid.clone(), removed_subscribers).as_message()
+}
+
+fn handle_topic_list(state: &BrokerState, _request: TopicListRequest) -> Message {
+    let mut topics: Vec<String> = state.topics.keys().cloned().collect();
+    topics.sort();
+
+    TopicListReply::new(state.broker_uuid.clone(), topics).as_message()
+}
+
+fn handle_topic_describe(state: &BrokerState, request: TopicDescribeRequest) -> Message {
+    Span::current().record("topic", request.topic.as_str());
+    let topic_data = match state.topics.get(&request.topic) {
+        Some(topic_data) => topic_data,
+        None => return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message()
+    };
+
+    let mut reply = TopicDescribeReply::new(request.topic.clone(), state.broker_uuid.clone(), topic_data.settings.clone());
+    reply.post_counters = topic_data.post_counters();
+    for band in topic_data.bands.values() {
+        reply.retained_posts += band.posts.len() as u64;
+        reply.byte_size += band.posts.values().map(|post| post.payload.len() as u64).sum::<u64>();
+    }
+    reply.scheduled_posts = topic_data.scheduled.len() as u64;
+    reply.retained_posts += reply.scheduled_posts;
+    reply.byte_size += topic_data.scheduled.iter().map(|scheduled| scheduled.post.payload.len() as u64).sum::<u64>();
+    reply.subscriber_offsets = state.subs.iter()
+        .filter(|(_, sub_data)| sub_data.topic == request.topic)
+        .map(|(sub_id, sub_data)| (sub_id.clone(), sub_data.last_read_posts.clone()))
+        .collect();
+
+    reply.as_message()
+}
+
+fn handle_admin_subscribers(state: &BrokerState, request: SubscribersRequest) -> Message {
+    let mut subscribers: Vec<SubscriberInfo> = state.subs.iter()
+        .filter(|(_, sub_data)| request.topic.as_ref().is_none_or(|topic| *topic == sub_data.topic))
+        .map(|(sub_id, sub_data)| SubscriberInfo {
+            sub_id: sub_id.clone(),
+            topic: sub_data.topic.clone(),
+            status: format!("{:?}", sub_data.status),
+            session: sub_data.session,
+            last_seen: sub_data.last_seen,
+            last_read_posts: sub_data.last_read_posts.clone(),
+            lag: state.topics.get(&sub_data.topic).map_or(0, |topic_data| topic_data.lag(sub_data))
+        })
+        .collect();
+    subscribers.sort_by(|a, b| a.sub_id.cmp(&b.sub_id));
+
+    SubscribersReply::new(state.broker_uuid.clone(), subscribers).as_message()
+}
+
+fn handle_admin_evict(state: &mut BrokerState, request: EvictRequest, config: &Config) -> Message {
+    Span::current().record("sub_id", request.sub_id.as_str());
+    // Subscriber does not exist
+    let sub_data = match state.subs.remove(&request.sub_id) {
+        Some(sub_data) => sub_data,
+        None => return BrokerErrorMessage::new(BrokerErrorType::SubscriberNotRegistered, state.broker_uuid.clone()).as_message()
+    };
+    audit(config, &format!("ADMIN_EVICT sub_id={} topic={} session={:?} last_seen={} last_read_posts={:?}",
+        request.sub_id, sub_data.topic, sub_data.session, sub_data.last_seen, sub_data.last_read_posts));
+    warn!(topic = %sub_data.topic, "Evicted subscriber on admin request");
+    remove_read_posts(state, &sub_data.topic);
+
+    EvictReply::new(request.sub_id.clone(), sub_data.topic, state.broker_uuid.clone()).as_message()
+}
+
+fn handle_admin_purge(state: &mut BrokerState, request: PurgeRequest, config: &Config) -> Message {
+    Span::current().record("topic", request.topic.as_str());
+    let topic_data = match state.topics.get_mut(&request.topic) {
+        Some(topic_data) => topic_data,
+        None => return BrokerErrorMessage::new(BrokerErrorType::InhexistantTopic, state.broker_uuid.clone()).as_message()
+    };
+
+    // Post numbers are kept so subscribers just skip over the purged posts
+    let mut purged_posts = topic_data.scheduled.len() as u64;
+    topic_data.scheduled.clear();
+    for band in topic_data.bands.values_mut() {
+        purged_posts += band.posts.len() as u64;
+        band.posts.clear();
+    }
+    audit(config, &format!("PURGE topic={} purged_posts={}", request.topic, purged_posts));
+    warn!(purged_posts, "Purged topic on admin request");
+
+    PurgeReply::new(request.topic.clone(), state.broker_uuid.clone(), purged_posts).as_message()
+}
+
+fn handle_admin_snapshot(state: &BrokerState, _request: SnapshotRequest, config: &Config) -> Message {
+    let start = Instant::now();
+    if let Err(err) = save_state(state, config) {
+        error!(%err, "Couldn't save the broker state");
+        return BrokerErrorMessage::snapshot_failed(state.broker_uuid.clone(), &err.to_string()).as_message();
+    }
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let path = config.data_path(STATE_FILE_PATH);
+    let bytes = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+
+    SnapshotReply::new(state.broker_uuid.clone(), path.to_string_lossy().into_owned(), bytes, duration_ms).as_message()
+}
+
+fn topic_limit_reached(state: &BrokerState, config: &Config) -> bool {
+    config.limits.max_topics.is_some_and(|max_topics| state.topics.len() as u64 >= max_topics)
+}
+
+fn create_topic(state: &mut BrokerState, config: &Config, topic: &str, settings: TopicSettings) {
+    audit(config, &format!("CREATE_TOPIC topic={} settings={:?}", topic, settings));
+    info!(topic, ?settings, "Created topic");
+    state.topics.insert(topic.to_owned(), TopicData::new(settings));
+}
+
+// Move the post delivered to a subscriber into its dead-letter topic and skip it
+fn dead_letter_post(state: &mut BrokerState, config: &Config, sub_id: &str, reason: &str) {
+    let subscriber_data = state.subs.get_mut(sub_id).unwrap();
+    let priority = subscriber_data.delivered_priority;
+    let post_no = subscriber_data.delivered_post;
+    let delivery_attempts = subscriber_data.delivery_attempts;
+    let topic = subscriber_data.topic.clone();
+    let dead_letter_topic = subscriber_data.dead_letter_topic();
+    subscriber_data.mark_read(priority, post_no);
+    subscriber_data.change_status(SubscriberStatus::WaitingGet);
+    subscriber_data.redeliver_at_ms = 0;
+
+    let dead_post = state.topics.get(&topic).and_then(|topic_data| topic_data.get_post(priority, post_no)).map(|post| {
+        let mut headers = post.headers.clone();
+        headers.insert(DEAD_LETTER_REASON_HEADER.to_owned(), reason.to_owned());
+        headers.insert(DEAD_LETTER_SUB_ID_HEADER.to_owned(), sub_id.to_owned());
+        headers.insert(ORIGINAL_TOPIC_HEADER.to_owned(), topic.clone());
+        headers.insert(ORIGINAL_POST_NO_HEADER.to_owned(), post_no.to_string());
+        headers.insert(DELIVERY_ATTEMPTS_HEADER.to_owned(), delivery_attempts.to_string());
+        let mut dead_post = Post::new(post.payload.clone(), post.expires_at, priority, headers, post.metadata.pub_id.clone());
+        dead_post.metadata.correlation_id = post.metadata.correlation_id.clone();
+        dead_post.compression = post.compression;
+        dead_post
+    });
+
+    let dead_letter_topic_exists = state.topics.contains_key(&dead_letter_topic);
+    if let Some(dead_post) = dead_post {
+        let refusal = match dead_letter_topic_exists {
+            true => None,
+            false if !config.auto_create_topics.on_dead_letter() => Some("topics are never created automatically"),
+            false if topic_limit_reached(state, config) => Some("it would go over the topic limit"),
+            false => None
+        };
+        if let Some(refusal) = refusal {
+            error!(sub_id, topic, post_no, dead_letter_topic, refusal, "Couldn't create the dead-letter topic, dropping the post");
+            audit(config, &format!("DEAD_LETTER_DROPPED sub_id={} topic={} priority={} post_no={} dead_letter_topic={} attempts={} reason={:?}",
+                sub_id, topic, priority, post_no, dead_letter_topic, delivery_attempts, reason));
+        } else {
+            if !dead_letter_topic_exists {
+                create_topic(state, config, &dead_letter_topic, TopicSettings::default());
+            }
+            let dead_letter_topic_data = state.topics.get_mut(&dead_letter_topic).unwrap();
+            dead_letter_topic_data.dead_letter = true;
+            let dead_letter_post_no = dead_letter_topic_data.add_post(dead_post);
+            audit(config, &format!("DEAD_LETTER sub_id={} topic={} priority={} post_no={} dead_letter_topic={} dead_letter_post_no={} attempts={} reason={:?}",
+                sub_id, topic, priority, post_no, dead_letter_topic, dead_letter_post_no, delivery_attempts, reason));
+        }
+    }
+
+    remove_read_posts(state, &topic);
+}
+
+// Remove the posts of a topic that were already read by all of its subscribers
+fn remove_read_posts(state: &mut BrokerState, topic: &str) {
+    let topic_data = match state.topics.get_mut(topic) {
+        Some(topic_data) => topic_data,
+        None => return
+    };
+    for (priority, band) in topic_data.bands.iter_mut() {
+        let mut min_last_read_post: u64 = u64::MAX;
+        for (_, sub_data) in state.subs.iter() {
+            let last_read_post = *sub_data.last_read_posts.get(priority).unwrap_or(&0);
+            if sub_data.topic == topic && last_read_post < min_last_read_post {
+                min_last_read_post = last_read_post;
+            }
+        }
+        // Nobody read the posts of a dead-letter topic without subscribers yet
+        if topic_data.dead_letter && min_last_read_post == u64::MAX {
+            continue;
+        }
+
+        let mut keys_to_remove: HashSet<String> = HashSet::new();
+        for (post_no, _) in band.posts.iter() {
+            if post_no.parse::<u64>().unwrap() <= min_last_read_post {
+                keys_to_remove.insert(post_no.clone());
+            }
+        }
+        band.posts.retain(|k, _| !keys_to_remove.contains(k));
+    }
+}
+
+// Make the delayed posts that are due visible to subscribers
+fn release_scheduled_posts(state: &mut BrokerState) -> bool {
+    let now_ms = now_ms();
+    let mut released_posts = false;
+    for (topic, topic_data) in state.topics.iter_mut() {
+        let released = topic_data.release_scheduled_posts(now_ms);
+        if released > 0 {
+            debug!(released, topic, "Released scheduled posts");
+            released_posts = true;
+        }
+    }
+    released_posts
+}
+
+// Reclaim the posts whose time-to-live is over
+fn remove_expired_posts(state: &mut BrokerState) -> bool {
+    let now = now();
+    let mut removed_posts = false;
+    for (topic, topic_data) in state.topics.iter_mut() {
+        for (priority, band) in topic_data.bands.iter_mut() {
+            let posts_before = band.posts.len();
+            band.posts.retain(|_, post| !post.is_expired(now));
+            if band.posts.len() != posts_before {
+                debug!(removed = posts_before - band.posts.len(), priority, topic, "Removed expired posts");
+                removed_posts = true;
+            }
+        }
+    }
+    removed_posts
+}
+
+// Evict the subscribers whose session timed out so they stop holding back their topic's posts
+fn evict_expired_subscribers(state: &mut BrokerState, config: &Config) -> bool {
+    let now = now();
+    let expired_subs: Vec<String> = state.subs.iter()
+        .filter(|(_, sub_data)| sub_data.is_expired(now))
+        .map(|(sub_id, _)| sub_id.clone())
+        .collect();
+
+    for sub_id in expired_subs.iter() {
+        let sub_data = state.subs.remove(sub_id).unwrap();
+        audit(config, &format!("EVICT sub_id={} topic={} session={:?} last_seen={} last_read_posts={:?}",
+            sub_id, sub_data.topic, sub_data.session, sub_data.last_seen, sub_data.last_read_posts));
+        info!(sub_id, topic = %sub_data.topic, "Evicted inactive subscriber");
+        remove_read_posts(state, &sub_data.topic);
+    }
+
+    !expired_subs.is_empty()
+}
+
+fn handle_requests(socket: &zmq::Socket, state: &mut BrokerState, config: &Config, metrics: &Mutex<Metrics>, replay_cache: &mut ReplayCache) {
+    // Every frame is read so the socket is always ready to send the reply
+    let req_frames: Vec<Vec<u8>> = match socket.recv_multipart(0) {
+        Ok(frames) => frames,
+        Err(err) => {
+            warn!(%err, "Couldn't receive request");
+            return;
+        }
+    };
+
+    let rep_frames = handle_request(state, config, metrics, replay_cache, req_frames);
+    if let Err(err) = socket.send_multipart(rep_frames, 0) {
+        error!(%err, "Couldn't send reply");
+    }
+}
+
+// Decodes a request and handles it, the reply is encoded with the codec of the request
+fn handle_request(state: &mut BrokerState, config: &Config, metrics: &Mutex<Metrics>, replay_cache: &mut ReplayCache, req_frames: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    // The signature covers the frames of the message, so it is checked before they are decoded
+    let decoded = Signature::split(req_frames).and_then(|(signature, req_frames)| {
+        let client_id = authenticate(&config.auth, signature.as_ref(), &req_frames, now_ms());
+        codec::decode_frames(req_frames).map(|(codec, req_message)| {
+            // Only requests with a valid signature are remembered, so forged ones can't take correlation ids
+            let client_id = client_id.and_then(|client_id| match (client_id, &signature) {
+                (Some(client_id), Some(signature)) => replay_cache.remember(&client_id, &req_message.correlation_id, signature.timestamp_ms)
+                    .map(|_| Some(client_id)),
+                (client_id, _) => Ok(client_id)
+            });
+            (codec, req_message, client_id)
+        })
+    });
+    let (codec, rep_message) = match decoded {
+        Ok((codec, req_message, client_id)) => (codec, handle_message(state, config, metrics, req_message, client_id)),
+        // Requests that can't be decoded get a MalformedRequest reply
+        Err(details) => {
+            warn!(%details, "Received malformed request");
+            metrics.lock().unwrap().observe_request(MALFORMED_MSG_TYPE, Duration::ZERO, Some(format!("{:?}", BrokerErrorType::MalformedRequest)));
+            let codec: &dyn Codec = &codec::BsonCodec;
+            (codec, BrokerErrorMessage::malformed(state.broker_uuid.clone(), &details).as_message())
+        }
+    };
+
+    codec::encode_frames(codec, rep_message).unwrap_or_else(|err| {
+        error!(%err, codec = codec.name(), "Couldn't encode reply");
+        let rep_message = BrokerErrorMessage::malformed(state.broker_uuid.clone(), &format!("the reply can't be encoded in {}", codec.name())).as_message();
+        vec![rep_message.to_bytes().unwrap()]
+    })
+}
+
+// Client id of a signed request, None for unsigned requests when authentication is optional
+fn authenticate(auth: &AuthConfig, signature: Option<&Signature>, req_frames: &[Vec<u8>], now_ms: u64) -> Result<Option<String>, String> {
+    let signature = match signature {
+        Some(signature) => signature,
+        None if auth.required => return Err("the request is not signed".to_owned()),
+        None => return Ok(None)
+    };
+    let secret = auth.clients.get(&signature.client_id).ok_or(format!("unknown client '{}'", signature.client_id))?;
+    let clock_skew_ms = now_ms.abs_diff(signature.timestamp_ms);
+    if clock_skew_ms > auth.max_clock_skew_ms {
+        return Err(format!("the request was signed {} ms away from the broker clock", clock_skew_ms));
+    }
+    if !signature.verify(secret, req_frames) {
+        return Err(format!("invalid signature for client '{}'", signature.client_id));
+    }
+    Ok(Some(signature.client_id.clone()))
+}
+
+fn handle_message(state: &mut BrokerState, config: &Config, metrics: &Mutex<Metrics>, mut req_message: Message, client_id: Result<Option<String>, String>) -> Message {
+    let msg_type = req_message.msg_type.clone();
+    let correlation_id = req_message.correlation_id.clone();
+    let version = req_message.version;
+    let span = info_span!("request", msg_type = %msg_type, correlation_id = %correlation_id, version, client_id = field::Empty,
+        sub_id = field::Empty, pub_id = field::Empty, topic = field::Empty, message_uuid = field::Empty, post_no = field::Empty);
+    let _enter = span.enter();
+    debug!("Received new request");
+    let start = Instant::now();
+    let payload_frame = req_message.payload_frame.take();
+    if let Ok(Some(client_id)) = &client_id {
+        span.record("client_id", client_id.as_str());
+    }
+    // HELLO is what negotiates the version, so it is answered whatever version the client sent it in
+    let rep_message = if msg_type != HELLO_HEAD && !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        BrokerErrorMessage::new(BrokerErrorType::UnsupportedVersion, state.broker_uuid.clone()).as_message()
+    } else if let Err(reason) = &client_id {
+        // The reason is only logged so clients can't probe which client ids exist
+        warn!(%reason, "Couldn't authenticate request");
+        BrokerErrorMessage::new(BrokerErrorType::Unauthenticated, state.broker_uuid.clone()).as_message()
+    } else {
+        match ClientRequest::from_message(req_message) {
+            Ok(mut request) => {
+                if version < PRIORITY_VERSION {
+                    acknowledge_delivered_priority(state, &mut request);
+                }
+                dispatch(state, config, request, payload_frame, &correlation_id, client_id.ok().flatten().as_deref())
+            },
+            Err(DeserializationErrors::IncompatibleMessageType) =>
+                BrokerErrorMessage::new(BrokerErrorType::UnknownMessage, state.broker_uuid.clone()).as_message(),
+            Err(DeserializationErrors::InvalidMessageStructure(err)) => {
+                let details = format!("invalid payload: {}", err);
+                warn!(%details, "Received malformed request payload");
+                BrokerErrorMessage::malformed(state.broker_uuid.clone(), &details).as_message()
+            }
+        }
+    };
+
+    let error = match rep_message.msg_type.as_str() {
+        ERROR_HEAD => bson::from_bson::<BrokerErrorMessage>(rep_message.payload.clone()).ok(),
+        _ => None
+    };
+    let error_type = error.as_ref().map(|error| format!("{:?}", error.error_type));
+    match &error_type {
+        Some(error_type) => info!(error_type = %error_type, "Refused request"),
+        None => debug!(reply = %rep_message.msg_type, "Handled request")
+    }
+    metrics.lock().unwrap().observe_request(&msg_type, start.elapsed(), error_type);
+
+    // A HELLO from a client newer than the broker is answered in the newest version the broker knows
+    let version = version.clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+    // Older clients can't decode the error types added after their version
+    let rep_message = match error {
+        Some(error) if error.error_type.since_version() > version => error.for_version(version).as_message(),
+        _ => rep_message
+    };
+    rep_message.with_correlation_id(correlation_id).with_version(version)
+}
+
+// Requests without a priority acknowledge the post last delivered to their subscriber, whatever its priority
+fn acknowledge_delivered_priority(state: &BrokerState, request: &mut ClientRequest) {
+    let delivered_priority = |sub_id: &String| state.subs.get(sub_id).map(|subscriber_data| subscriber_data.delivered_priority);
+    match request {
+        ClientRequest::Ack(request) => request.priority = delivered_priority(&request.sub_id).unwrap_or(request.priority),
+        ClientRequest::Nack(request) => request.priority = delivered_priority(&request.sub_id).unwrap_or(request.priority),
+        _ => {}
+    }
+}
+
+// Action a request needs to be allowed to and the topic it is about, None for the ones every client may send
+fn required_access(state: &BrokerState, request: &ClientRequest) -> Option<(Action, Option<String>)> {
+    let subscriber_topic = |sub_id: &String| state.subs.get(sub_id).map(|sub_data| sub_data.topic.clone());
+    match request {
+        ClientRequest::Hello(_) => None,
+        ClientRequest::Get(request) => Some((Action::Subscribe, Some(request.topic.clone()))),
+        ClientRequest::Ack(request) => Some((Action::Subscribe, subscriber_topic(&request.sub_id))),
+        ClientRequest::Nack(request) => Some((Action::Subscribe, subscriber_topic(&request.sub_id))),
+        ClientRequest::Put(request) => Some((Action::Publish, Some(request.topic.clone()))),
+        ClientRequest::Subscribe(request) => Some((Action::Subscribe, Some(request.topic.clone()))),
+        ClientRequest::Unsubscribe(request) => Some((Action::Subscribe, subscriber_topic(&request.sub_id))),
+        ClientRequest::TopicCreate(request) => Some((Action::Admin, Some(request.topic.clone()))),
+        ClientRequest::TopicDelete(request) => Some((Action::Admin, Some(request.topic.clone()))),
+        ClientRequest::TopicList(_) => Some((Action::Admin, None)),
+        ClientRequest::TopicDescribe(request) => Some((Action::Admin, Some(request.topic.clone()))),
+        ClientRequest::AdminSubscribers(request) => Some((Action::Admin, request.topic.clone())),
+        ClientRequest::AdminEvict(request) => Some((Action::Admin, subscriber_topic(&request.sub_id))),
+        ClientRequest::AdminPurge(request) => Some((Action::Admin, Some(request.topic.clone()))),
+        ClientRequest::AdminSnapshot(_) => Some((Action::Admin, None))
+    }
+}
+
+// Subscription a request acts on on behalf of its subscriber
+fn subscription_of(request: &ClientRequest) -> Option<&String> {
+    match request {
+        ClientRequest::Get(request) => Some(&request.sub_id),
+        ClientRequest::Ack(request) => Some(&request.sub_id),
+        ClientRequest::Nack(request) => Some(&request.sub_id),
+        ClientRequest::Unsubscribe(request) => Some(&request.sub_id),
+        _ => None
+    }
+}
+
+// Dead-letter topic a subscription may publish to, None when its posts are never dead-lettered
+fn dead_letter_access(state: &BrokerState, request: &ClientRequest) -> Option<String> {
+    let ClientRequest::Subscribe(request) = request else {
+        return None;
+    };
+    let topic_max_delivery_attempts = state.topics.get(&request.topic).and_then(|topic_data| topic_data.settings.max_delivery_attempts);
+    request.max_delivery_attempts.or(topic_max_delivery_attempts)
+        .map(|_| resolve_dead_letter_topic(&request.topic, request.dead_letter_topic.as_ref()))
+}
+
+fn dispatch(state: &mut BrokerState, config: &Config, request: ClientRequest, payload_frame: Option<Vec<u8>>, correlation_id: &str, client_id: Option<&str>) -> Message {
+    if let Some((action, topic)) = required_access(state, &request) {
+        if !config.acl.allows(client_id, action, topic.as_deref()) {
+            info!(?action, topic, "Access denied by the ACL");
+            return BrokerErrorMessage::new(BrokerErrorType::Unauthorized, state.broker_uuid.clone()).as_message();
+        }
+    }
+    if let Some(subscriber_data) = subscription_of(&request).and_then(|sub_id| state.subs.get(sub_id)) {
+        if subscriber_data.client_id.as_deref() != client_id {
+            info!(owner = ?subscriber_data.client_id, "Subscription belongs to another client");
+            return BrokerErrorMessage::new(BrokerErrorType::Unauthorized, state.broker_uuid.clone()).as_message();
+        }
+    }
+    // Rejected posts are published to the dead-letter topic on behalf of the subscriber
+    if let Some(dead_letter_topic) = dead_letter_access(state, &request) {
+        if !config.acl.allows(client_id, Action::Publish, Some(&dead_letter_topic)) {
+            info!(action = ?Action::Publish, topic = dead_letter_topic, "Access to the dead-letter topic denied by the ACL");
+            return BrokerErrorMessage::new(BrokerErrorType::Unauthorized, state.broker_uuid.clone()).as_message();
+        }
+    }
+
+    match request {
+        ClientRequest::Hello(request) => handle_hello(state, request),
+        ClientRequest::Get(request) => handle_get(state, request, config),
+        ClientRequest::Ack(request) => handle_get_ack(state, request),
+        ClientRequest::Nack(request) => handle_get_nack(state, request, config),
+        ClientRequest::Put(mut request) => {
+            // Payloads sent in their own frame are stored as they are
+            if let Some(payload) = payload_frame {
+                request.payload = payload;
+            }
+            handle_put(state, request, config, correlation_id.to_owned())
+        },
+        ClientRequest::Subscribe(request) => handle_sub(state, request, config, client_id),
+        ClientRequest::Unsubscribe(request) => handle_unsub(state, request),
+        ClientRequest::TopicCreate(request) => handle_topic_create(state, request, config),
+        ClientRequest::TopicDelete(request) => handle_topic_delete(state, request, config),
+        ClientRequest::TopicList(request) => handle_topic_list(state, request),
+        ClientRequest::TopicDescribe(request) => handle_topic_describe(state, request),
+        ClientRequest::AdminSubscribers(request) => handle_admin_subscribers(state, request),
+        ClientRequest::AdminEvict(request) => handle_admin_evict(state, request, config),
+        ClientRequest::AdminPurge(request) => handle_admin_purge(state, request, config),
+        ClientRequest::AdminSnapshot(request) => handle_admin_snapshot(state, request, config)
+    }
+}
+
+
+// Handles a sequence of requests against a fresh state, used by the fuzz target
+#[cfg(fuzzing)]
+pub fn fuzz_requests(requests: Vec<Vec<Vec<u8>>>) {
+    let config = Config::default();
+    let metrics = Mutex::new(Metrics::default());
+    let mut state = BrokerState::new();
+    let mut replay_cache = ReplayCache::default();
+    for req_frames in requests {
+        handle_request(&mut state, &config, &metrics, &mut replay_cache, req_frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::AutoCreatePolicy;
+    use meic_mq::auth::Credentials;
+
+    fn signed_request(auth: &mut AuthConfig) -> (Signature, Vec<Vec<u8>>) {
+        auth.clients.insert("alice".to_owned(), "secret".to_owned());
+        let signed_frames = Credentials::new("alice".to_owned(), "secret".to_owned()).sign(vec![b"message".to_vec()]);
+        let (signature, frames) = Signature::split(signed_frames).unwrap();
+        (signature.unwrap(), frames)
+    }
+
+    #[test]
+    fn clock_skew_is_allowed_up_to_the_limit() {
+        let mut auth = AuthConfig::default();
+        let (signature, frames) = signed_request(&mut auth);
+        let signed_at_ms = signature.timestamp_ms;
+        let max_skew_ms = auth.max_clock_skew_ms;
+
+        for now_ms in [signed_at_ms, signed_at_ms - max_skew_ms, signed_at_ms + max_skew_ms] {
+            assert_eq!(authenticate(&auth, Some(&signature), &frames, now_ms), Ok(Some("alice".to_owned())));
+        }
+        for now_ms in [signed_at_ms - max_skew_ms - 1, signed_at_ms + max_skew_ms + 1] {
+            assert!(authenticate(&auth, Some(&signature), &frames, now_ms).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_clients_and_unsigned_requests() {
+        let mut auth = AuthConfig::default();
+        let (signature, frames) = signed_request(&mut auth);
+        let now_ms = signature.timestamp_ms;
+        assert_eq!(authenticate(&auth, None, &frames, now_ms), Ok(None));
+        auth.required = true;
+        assert!(authenticate(&auth, None, &frames, now_ms).is_err());
+        auth.clients.clear();
+        assert!(authenticate(&auth, Some(&signature), &frames, now_ms).is_err());
+    }
+
+    fn topic_with_posts(priorities: &[u8]) -> TopicData {
+        let mut topic_data = TopicData::new(TopicSettings::default());
+        for priority in priorities {
+            topic_data.add_post(Post::new(vec![*priority], None, *priority, HashMap::new(), "publisher".to_owned()));
+        }
+        topic_data
+    }
+
+    fn subscriber() -> SubscriberData {
+        SubscriberData::new("orders".to_owned(), HashMap::new(), SessionType::default(), EPHEMERAL_SESSION_TIMEOUT_SECS,
+            None, None, DEFAULT_VISIBILITY_TIMEOUT_MS)
+    }
+
+    #[test]
+    fn higher_priority_bands_are_delivered_first() {
+        let topic_data = topic_with_posts(&[0, 5, 0, 9, 5]);
+        let mut subscriber_data = subscriber();
+        let mut delivered = Vec::new();
+        while let Some((priority, post_no, skipped, post)) = topic_data.next_post(&subscriber_data, now()) {
+            assert_eq!(skipped, 0);
+            assert_eq!(post.priority, priority);
+            delivered.push((priority, post_no));
+            subscriber_data.mark_read(priority, post_no);
+        }
+        assert_eq!(delivered, [(9, 1), (5, 1), (5, 2), (0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn expired_posts_are_skipped_within_their_band() {
+        let mut topic_data = topic_with_posts(&[3, 3, 3]);
+        topic_data.bands.get_mut("3").unwrap().posts.get_mut("1").unwrap().expires_at = Some(now() - 1);
+        topic_data.bands.get_mut("3").unwrap().posts.remove("2");
+        let (priority, post_no, skipped, _) = topic_data.next_post(&subscriber(), now()).unwrap();
+        assert_eq!((priority, post_no, skipped), (3, 3, 2));
+    }
+
+    #[test]
+    fn scheduled_posts_are_released_once_due() {
+        let mut topic_data = TopicData::new(TopicSettings::default());
+        for (deliver_at_ms, payload) in [(3_000, &b"third"[..]), (1_000, b"first"), (2_000, b"second")] {
+            topic_data.schedule_post(Post::new(payload.to_vec(), None, 0, HashMap::new(), "publisher".to_owned()), deliver_at_ms);
+        }
+
+        assert_eq!(topic_data.release_scheduled_posts(999), 0);
+        assert!(topic_data.next_post(&subscriber(), now()).is_none());
+
+        // Due posts are numbered in delivery time order, including the one due at exactly that time
+        assert_eq!(topic_data.release_scheduled_posts(2_000), 2);
+        assert_eq!(topic_data.scheduled.len(), 1);
+        assert_eq!(topic_data.get_post(0, 1).unwrap().payload, b"first");
+        assert_eq!(topic_data.get_post(0, 2).unwrap().payload, b"second");
+
+        assert_eq!(topic_data.release_scheduled_posts(10_000), 1);
+        assert!(topic_data.scheduled.is_empty());
+        assert_eq!(topic_data.get_post(0, 3).unwrap().payload, b"third");
+    }
+
+    // The data directory is removed by the test once it is done with it
+    fn config_in_temp_dir(name: &str) -> Config {
+        let data_dir = env::temp_dir().join(format!("broker_{}_{}", name, process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+        Config { data_dir: data_dir.to_string_lossy().into_owned(), ..Config::default() }
+    }
+
+    fn error_type(reply: Message) -> Option<BrokerErrorType> {
+        BrokerErrorMessage::from_message(reply).ok().map(|reply| reply.error_type)
+    }
+
+    fn send(state: &mut BrokerState, config: &Config, request: Message) -> Message {
+        handle_message(state, config, &Mutex::new(Metrics::default()), request, Ok(None))
+    }
+
+    fn fetch(state: &mut BrokerState, config: &Config) -> Option<GetReply> {
+        GetReply::from_message(send(state, config, GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).ok()
+    }
+
+    // Subscribers start after the posts published before them, so the posts are added once subscribed
+    fn subscribed_state(config: &Config, request: SubRequest) -> BrokerState {
+        let mut state = BrokerState::new();
+        assert!(SubReply::from_message(send(&mut state, config, request.as_message())).is_ok());
+        state.topics.insert("orders".to_owned(), topic_with_posts(&[0, 0]));
+        state
+    }
+
+    #[test]
+    fn posts_are_dead_lettered_once_their_attempts_are_exhausted() {
+        let config = config_in_temp_dir("dead_letter_threshold");
+        let mut state = subscribed_state(&config, SubRequest::new("worker".to_owned(), "orders".to_owned()).with_dead_letter(2, None));
+
+        let mut dead_lettered = Vec::new();
+        for attempt in 1..=2 {
+            let reply = fetch(&mut state, &config).unwrap();
+            assert_eq!((reply.message_no, reply.delivery_attempt), (1, attempt));
+            let nack = NackRequest::new("worker".to_owned(), 1, 0, "can't parse".to_owned(), None);
+            dead_lettered.push(NackReply::from_message(send(&mut state, &config, nack.as_message())).ok().unwrap().dead_lettered);
+        }
+        let audit_log = fs::read_to_string(config.data_path(AUDIT_LOG_PATH)).unwrap();
+        fs::remove_dir_all(&config.data_dir).unwrap();
+
+        assert_eq!(dead_lettered, [false, true]);
+        let dead_letter_topic = &state.topics["orders.dead_letter"];
+        assert!(dead_letter_topic.dead_letter);
+        let dead_post = dead_letter_topic.get_post(0, 1).unwrap();
+        assert_eq!(dead_post.payload, [0]);
+        assert_eq!(dead_post.headers[DEAD_LETTER_REASON_HEADER], "can't parse");
+        assert_eq!(dead_post.headers[DELIVERY_ATTEMPTS_HEADER], "2");
+        assert_eq!(dead_post.headers[ORIGINAL_POST_NO_HEADER], "1");
+        assert!(audit_log.contains("DEAD_LETTER sub_id=worker topic=orders priority=0 post_no=1 dead_letter_topic=orders.dead_letter"));
+        // The subscriber carries on with the next post
+        assert_eq!(fetch(&mut state, &config).unwrap().message_no, 2);
+    }
+
+    #[test]
+    fn unacknowledged_posts_are_redelivered_after_the_visibility_timeout() {
+        let config = config_in_temp_dir("visibility_timeout");
+        let mut state = subscribed_state(&config, SubRequest::new("worker".to_owned(), "orders".to_owned()).with_visibility_timeout(60_000));
+
+        assert_eq!(fetch(&mut state, &config).unwrap().delivery_attempt, 1);
+        let reply = send(&mut state, &config, GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message());
+        assert!(matches!(error_type(reply), Some(BrokerErrorType::AwaitingAck)));
+
+        state.subs.get_mut("worker").unwrap().redeliver_at_ms = now_ms() - 1;
+        let reply = fetch(&mut state, &config).unwrap();
+        assert_eq!((reply.message_no, reply.delivery_attempt), (1, 2));
+        assert!(AckReply::from_message(send(&mut state, &config, AckRequest::new("worker".to_owned(), 1, 0).as_message())).is_ok());
+        assert_eq!(fetch(&mut state, &config).unwrap().message_no, 2);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_posts_wait_for_their_requeue_delay() {
+        let config = config_in_temp_dir("requeue_wait");
+        let mut state = subscribed_state(&config, SubRequest::new("worker".to_owned(), "orders".to_owned()));
+
+        fetch(&mut state, &config).unwrap();
+        let nack = NackRequest::new("worker".to_owned(), 1, 0, "busy".to_owned(), Some(60_000));
+        assert!(!NackReply::from_message(send(&mut state, &config, nack.as_message())).ok().unwrap().dead_lettered);
+        let reply = send(&mut state, &config, GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message());
+        assert!(matches!(error_type(reply), Some(BrokerErrorType::RedeliveryDelayed)));
+
+        state.subs.get_mut("worker").unwrap().redeliver_at_ms = now_ms() - 1;
+        let reply = fetch(&mut state, &config).unwrap();
+        assert_eq!((reply.message_no, reply.delivery_attempt), (1, 2));
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn delayed_puts_are_held_until_due() {
+        let config = config_in_temp_dir("delayed_puts");
+        let mut state = BrokerState::new();
+        assert!(SubReply::from_message(send(&mut state, &config, SubRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).is_ok());
+
+        let put = |payload: &[u8], deliver_at_ms: u64| PutRequest::new("publisher".to_owned(), "orders".to_owned(), payload.to_vec())
+            .with_deliver_at(deliver_at_ms).as_message();
+        assert!(PutReply::from_message(send(&mut state, &config, put(b"later", now_ms() + 60_000))).is_ok());
+        assert!(PutReply::from_message(send(&mut state, &config, put(b"now", now_ms() - 1))).is_ok());
+        assert_eq!(state.topics["orders"].scheduled.len(), 1);
+
+        // Posts due in the past are delivered right away
+        assert!(!release_scheduled_posts(&mut state));
+        assert_eq!(state.topics["orders"].get_post(0, 1).unwrap().payload, b"now");
+        let reply = fetch(&mut state, &config).unwrap();
+        assert!(AckReply::from_message(send(&mut state, &config, AckRequest::new("worker".to_owned(), reply.message_no, 0).as_message())).is_ok());
+        assert!(matches!(error_type(send(&mut state, &config, GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message())),
+            Some(BrokerErrorType::NoPostsInTopic)));
+
+        state.topics.get_mut("orders").unwrap().scheduled[0].deliver_at_ms = now_ms() - 1;
+        assert!(release_scheduled_posts(&mut state));
+        assert_eq!(fetch(&mut state, &config).unwrap().message_no, 2);
+        assert_eq!(state.topics["orders"].get_post(0, 2).unwrap().payload, b"later");
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_payloads_are_refused_and_can_be_sent_again() {
+        let config = config_in_temp_dir("checksums");
+        let mut state = BrokerState::new();
+        assert!(SubReply::from_message(send(&mut state, &config, SubRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).is_ok());
+
+        let request = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec());
+        let mut corrupted = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"paylaod".to_vec());
+        corrupted.message_uuid = request.message_uuid.clone();
+        corrupted.checksum = request.checksum;
+        assert!(matches!(error_type(send(&mut state, &config, corrupted.as_message())), Some(BrokerErrorType::ChecksumMismatch)));
+        assert!(state.topics["orders"].get_post(0, 1).is_none());
+
+        // The refused put is not taken for a duplicate when it is sent again
+        assert!(PutReply::from_message(send(&mut state, &config, request.as_message())).is_ok());
+        let reply = fetch(&mut state, &config).unwrap();
+        assert_eq!(reply.checksum, request.checksum);
+        assert!(AckReply::from_message(send(&mut state, &config, AckRequest::new("worker".to_owned(), reply.message_no, 0).as_message())).is_ok());
+
+        // Puts of clients that don't send checksums are accepted and delivered with the one the broker computed
+        let mut unchecked = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"unchecked".to_vec());
+        unchecked.checksum = None;
+        assert!(PutReply::from_message(send(&mut state, &config, unchecked.as_message())).is_ok());
+        assert_eq!(fetch(&mut state, &config).unwrap().checksum, Some(checksum::compute(b"unchecked")));
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    fn error_reply(rep_frames: Vec<Vec<u8>>) -> BrokerErrorMessage {
+        let (_, rep_message) = codec::decode_frames(rep_frames).unwrap();
+        BrokerErrorMessage::from_message(rep_message).ok().unwrap()
+    }
+
+    #[test]
+    fn malformed_requests_get_error_replies_with_their_reason() {
+        let config = config_in_temp_dir("malformed_requests");
+        let metrics = Mutex::new(Metrics::default());
+        let mut state = BrokerState::new();
+        let mut replay_cache = ReplayCache::default();
+        let mut request = |req_frames: Vec<Vec<u8>>| handle_request(&mut state, &config, &metrics, &mut replay_cache, req_frames);
+
+        let reply = error_reply(request(vec![b"not a message".to_vec()]));
+        assert!(matches!(reply.error_type, BrokerErrorType::MalformedRequest));
+        let reply = error_reply(request(vec![b"yaml".to_vec(), b"topic: orders".to_vec()]));
+        assert!(matches!(reply.error_type, BrokerErrorType::MalformedRequest));
+        assert!(reply.description.ends_with("unsupported codec 'yaml'"));
+
+        let put = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec()).as_message();
+        let mut invalid_payload = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec()).as_message();
+        invalid_payload.payload = bson::bson!({ "topic": 1 });
+        let reply = error_reply(request(codec::encode_frames(&codec::BsonCodec, invalid_payload).unwrap()));
+        assert!(matches!(reply.error_type, BrokerErrorType::MalformedRequest));
+        assert!(reply.description.contains("invalid payload"));
+
+        let mut unknown_type = put;
+        unknown_type.msg_type = "FETCH".to_owned();
+        let reply = error_reply(request(codec::encode_frames(&codec::BsonCodec, unknown_type).unwrap()));
+        assert!(matches!(reply.error_type, BrokerErrorType::UnknownMessage));
+
+        assert!(state.topics.is_empty());
+        // Envelopes that can't be decoded are counted apart, their message type is unknown
+        assert!(metrics.lock().unwrap().render().contains("broker_requests_total{msg_type=\"MALFORMED\"} 2\n"));
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn topics_are_created_described_and_deleted() {
+        let config = config_in_temp_dir("topic_admin");
+        let mut state = BrokerState::new();
+        let create = |topic: &str| TopicCreateRequest::new(topic.to_owned(), TopicSettings::default().with_max_delivery_attempts(3)).as_message();
+
+        assert!(TopicCreateReply::from_message(send(&mut state, &config, create("orders"))).is_ok());
+        assert!(TopicCreateReply::from_message(send(&mut state, &config, create("invoices"))).is_ok());
+        assert!(matches!(error_type(send(&mut state, &config, create("orders"))), Some(BrokerErrorType::TopicAlreadyExists)));
+        let reply = TopicListReply::from_message(send(&mut state, &config, TopicListRequest::new().as_message())).ok().unwrap();
+        assert_eq!(reply.topics, ["invoices", "orders"]);
+
+        assert!(SubReply::from_message(send(&mut state, &config, SubRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).is_ok());
+        let put = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"order".to_vec()).with_priority(4);
+        assert!(PutReply::from_message(send(&mut state, &config, put.as_message())).is_ok());
+        let reply = TopicDescribeReply::from_message(send(&mut state, &config, TopicDescribeRequest::new("orders".to_owned()).as_message())).ok().unwrap();
+        assert_eq!(reply.settings.max_delivery_attempts, Some(3));
+        assert_eq!((reply.post_counters["4"], reply.retained_posts, reply.byte_size), (1, 1, 5));
+        assert!(reply.subscriber_offsets.contains_key("worker"));
+
+        let reply = TopicDeleteReply::from_message(send(&mut state, &config, TopicDeleteRequest::new("orders".to_owned()).as_message())).ok().unwrap();
+        assert_eq!(reply.removed_subscribers, ["worker"]);
+        assert!(!state.subs.contains_key("worker"));
+        assert!(matches!(error_type(send(&mut state, &config, TopicDeleteRequest::new("orders".to_owned()).as_message())),
+            Some(BrokerErrorType::InhexistantTopic)));
+        assert!(matches!(error_type(send(&mut state, &config, TopicDescribeRequest::new("orders".to_owned()).as_message())),
+            Some(BrokerErrorType::InhexistantTopic)));
+        let audit_log = fs::read_to_string(config.data_path(AUDIT_LOG_PATH)).unwrap();
+        fs::remove_dir_all(&config.data_dir).unwrap();
+        assert!(audit_log.contains("CREATE_TOPIC topic=invoices"));
+        assert!(audit_log.contains("DELETE_TOPIC topic=orders removed_subscribers=[\"worker\"]"));
+    }
+
+    #[test]
+    fn admin_requests_inspect_and_operate_the_broker() {
+        let config = config_in_temp_dir("admin");
+        let mut state = subscribed_state(&config, SubRequest::new("worker".to_owned(), "orders".to_owned()));
+        state.topics.get_mut("orders").unwrap().schedule_post(Post::new(b"later".to_vec(), None, 0, HashMap::new(), "publisher".to_owned()), now_ms() + 60_000);
+
+        let reply = SubscribersReply::from_message(send(&mut state, &config, SubscribersRequest::new(Some("orders".to_owned())).as_message())).ok().unwrap();
+        assert_eq!(reply.subscribers.len(), 1);
+        assert_eq!((reply.subscribers[0].sub_id.as_str(), reply.subscribers[0].lag), ("worker", 2));
+        let reply = SubscribersReply::from_message(send(&mut state, &config, SubscribersRequest::new(Some("invoices".to_owned())).as_message())).ok().unwrap();
+        assert!(reply.subscribers.is_empty());
+
+        let reply = SnapshotReply::from_message(send(&mut state, &config, SnapshotRequest::new().as_message())).ok().unwrap();
+        assert_eq!(reply.path, config.data_path(STATE_FILE_PATH).to_string_lossy());
+        assert!(reply.bytes > 0);
+        assert_eq!(state_file::load_state(&reply.path).unwrap().subs.len(), 1);
+
+        let reply = PurgeReply::from_message(send(&mut state, &config, PurgeRequest::new("orders".to_owned()).as_message())).ok().unwrap();
+        assert_eq!(reply.purged_posts, 3);
+        assert_eq!(state.topics["orders"].retained_posts(), 0);
+        let reply = EvictReply::from_message(send(&mut state, &config, EvictRequest::new("worker".to_owned()).as_message())).ok().unwrap();
+        assert_eq!(reply.topic, "orders");
+        assert!(state.subs.is_empty());
+        assert!(matches!(error_type(send(&mut state, &config, EvictRequest::new("worker".to_owned()).as_message())),
+            Some(BrokerErrorType::SubscriberNotRegistered)));
+
+        let audit_log = fs::read_to_string(config.data_path(AUDIT_LOG_PATH)).unwrap();
+        fs::remove_dir_all(&config.data_dir).unwrap();
+        assert!(audit_log.contains("PURGE topic=orders purged_posts=3"));
+        assert!(audit_log.contains("ADMIN_EVICT sub_id=worker topic=orders"));
+    }
+
+    #[test]
+    fn correlation_ids_follow_posts_to_their_subscribers() {
+        let config = config_in_temp_dir("correlation_ids");
+        let mut state = BrokerState::new();
+        assert!(SubReply::from_message(send(&mut state, &config, SubRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).is_ok());
+
+        let put = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"order".to_vec()).as_message().with_correlation_id("put-1".to_owned());
+        assert_eq!(send(&mut state, &config, put).correlation_id, "put-1");
+        let get = GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message().with_correlation_id("get-1".to_owned());
+        let reply = send(&mut state, &config, get);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+
+        assert_eq!(reply.correlation_id, "get-1");
+        let reply = GetReply::from_message(reply).ok().unwrap();
+        assert_eq!((reply.metadata.pub_id.as_str(), reply.metadata.correlation_id.as_str()), ("publisher", "put-1"));
+        // Errors are answered with the correlation id of their request too
+        let error = send(&mut state, &config, GetRequest::new("ghost".to_owned(), "orders".to_owned()).as_message().with_correlation_id("get-2".to_owned()));
+        assert_eq!(error.correlation_id, "get-2");
+        assert!(matches!(error_type(error), Some(BrokerErrorType::SubscriberNotRegistered)));
+    }
+
+    #[test]
+    fn requeue_delays_over_the_limit_are_refused() {
+        let config = config_in_temp_dir("requeue_delay");
+        let mut state = BrokerState::new();
+        state.topics.insert("orders".to_owned(), topic_with_posts(&[0]));
+        let mut subscriber_data = subscriber();
+        subscriber_data.mark_delivered(0, 1);
+        subscriber_data.change_status(SubscriberStatus::WaitingAck);
+        state.subs.insert("worker".to_owned(), subscriber_data);
+
+        let request = NackRequest::new("worker".to_owned(), 1, 0, "failed".to_owned(), Some(u64::MAX));
+        assert!(matches!(error_type(handle_get_nack(&mut state, request, &config)), Some(BrokerErrorType::LimitExceeded)));
+        assert!(matches!(state.subs["worker"].status, SubscriberStatus::WaitingAck));
+        let saved = save_state(&state, &config);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+        saved.unwrap();
+
+        let request = NackRequest::new("worker".to_owned(), 1, 0, "failed".to_owned(), Some(config.limits.max_delay_ms));
+        assert!(NackReply::from_message(handle_get_nack(&mut state, request, &config)).is_ok());
+    }
+
+    #[test]
+    fn dead_letter_topics_are_only_created_when_allowed() {
+        let mut config = config_in_temp_dir("dead_letter_never");
+        config.auto_create_topics = AutoCreatePolicy::Never;
+        let mut state = BrokerState::new();
+        state.topics.insert("orders".to_owned(), topic_with_posts(&[0, 0]));
+        let mut subscriber_data = SubscriberData::new("orders".to_owned(), HashMap::new(), SessionType::default(), EPHEMERAL_SESSION_TIMEOUT_SECS,
+            Some(1), None, DEFAULT_VISIBILITY_TIMEOUT_MS);
+        subscriber_data.mark_delivered(0, 1);
+        subscriber_data.change_status(SubscriberStatus::WaitingAck);
+        state.subs.insert("worker".to_owned(), subscriber_data);
+
+        let request = NackRequest::new("worker".to_owned(), 1, 0, "failed".to_owned(), None);
+        let reply = NackReply::from_message(handle_get_nack(&mut state, request, &config)).ok().unwrap();
+        let audit_log = fs::read_to_string(config.data_path(AUDIT_LOG_PATH)).unwrap();
+        fs::remove_dir_all(&config.data_dir).unwrap();
+
+        // The post is dropped and the subscriber moves past it
+        assert!(reply.dead_lettered);
+        assert!(!state.topics.contains_key("orders.dead_letter"));
+        assert!(audit_log.contains("DEAD_LETTER_DROPPED sub_id=worker topic=orders priority=0 post_no=1"));
+        assert_eq!(state.subs["worker"].last_read_post(0), 1);
+    }
+
+    #[test]
+    fn version_1_clients_get_replies_they_can_decode() {
+        let config = Config::default();
+        let metrics = Mutex::new(Metrics::default());
+        let mut state = BrokerState::new();
+        state.topics.insert("orders".to_owned(), topic_with_posts(&[0, 7]));
+        state.subs.insert("worker".to_owned(), subscriber());
+        let mut send = |request: Message| handle_message(&mut state, &config, &metrics, request.with_version(1), Ok(None));
+
+        let reply = GetReply::from_message(send(GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).ok().unwrap();
+        assert_eq!((reply.priority, reply.message_no), (7, 1));
+        // Fetching again before acknowledging is refused with an error type version 1 knows
+        assert!(matches!(error_type(send(GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message())), Some(BrokerErrorType::NoPostsInTopic)));
+        // Acknowledgements of version 1 have no priority and are taken for the delivered post
+        assert!(AckReply::from_message(send(AckRequest::new("worker".to_owned(), 1, 0).as_message())).is_ok());
+        let reply = GetReply::from_message(send(GetRequest::new("worker".to_owned(), "orders".to_owned()).as_message())).ok().unwrap();
+        assert_eq!((reply.priority, reply.message_no), (0, 1));
+    }
+
+    #[test]
+    fn expiry_times_stay_within_the_state_file_range() {
+        let config = config_in_temp_dir("expiry_range");
+        let mut state = BrokerState::new();
+
+        let settings = TopicSettings::default().with_default_ttl(u64::MAX);
+        let reply = handle_topic_create(&mut state, TopicCreateRequest::new("orders".to_owned(), settings), &config);
+        assert!(matches!(error_type(reply), Some(BrokerErrorType::MalformedRequest)));
+
+        let mut request = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"late".to_vec());
+        request.expires_at = Some(u64::MAX);
+        assert!(matches!(error_type(handle_put(&mut state, request, &config, String::new())), Some(BrokerErrorType::MalformedRequest)));
+        let request = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"later".to_vec()).with_deliver_at(u64::MAX);
+        assert!(matches!(error_type(handle_put(&mut state, request, &config, String::new())), Some(BrokerErrorType::MalformedRequest)));
+
+        // Expiry times taken from the default time-to-live are capped instead
+        let settings = TopicSettings::default().with_default_ttl(MAX_TIMESTAMP);
+        assert!(TopicCreateReply::from_message(handle_topic_create(&mut state, TopicCreateRequest::new("orders".to_owned(), settings), &config)).is_ok());
+        let request = PutRequest::new("publisher".to_owned(), "orders".to_owned(), b"kept".to_vec());
+        assert!(PutReply::from_message(handle_put(&mut state, request, &config, String::new())).is_ok());
+        assert_eq!(state.topics["orders"].get_post(0, 1).unwrap().expires_at, Some(MAX_TIMESTAMP));
+
+        let saved = save_state(&state, &config);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+        saved.unwrap();
+    }
+}
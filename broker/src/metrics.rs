@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{ self, Read, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use tracing::{ error, info };
+
+use super::BrokerState;
+use super::shutdown::ShutdownHandle;
+
+// How long a scrape waits for the broker loop to take the gauges from the state, it checks at least every sweep
+const STATE_WAIT: Duration = Duration::from_secs(2);
+const STATE_POLL: Duration = Duration::from_millis(10);
+// How often the listener checks for a shutdown between connections
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: HashMap<String, u64>,
+    request_seconds: HashMap<String, f64>,
+    errors: HashMap<String, u64>,
+    topic_posts: HashMap<String, u64>,
+    topic_bytes: HashMap<String, u64>,
+    // Indexed by subscriber and topic
+    subscriber_lag: HashMap<(String, String), u64>,
+    dedup_set_size: u64,
+    snapshots: u64,
+    snapshot_seconds: f64,
+    // Set by a scrape until the gauges are taken from the state again
+    state_requested: bool
+}
+
+impl Metrics {
+    pub fn observe_request(&mut self, msg_type: &str, duration: Duration, error_type: Option<String>) {
+        *self.requests.entry(msg_type.to_owned()).or_insert(0) += 1;
+        *self.request_seconds.entry(msg_type.to_owned()).or_insert(0.0) += duration.as_secs_f64();
+        if let Some(error_type) = error_type {
+            *self.errors.entry(error_type).or_insert(0) += 1;
+        }
+    }
+
+    pub fn observe_snapshot(&mut self, duration: Duration) {
+        self.snapshots += 1;
+        self.snapshot_seconds = duration.as_secs_f64();
+    }
+
+    pub fn state_requested(&self) -> bool {
+        self.state_requested
+    }
+
+    // Gauges are taken from the state instead of being tracked by every handler
+    pub fn observe_state(&mut self, state: &BrokerState) {
+        self.state_requested = false;
+        self.topic_posts.clear();
+        self.topic_bytes.clear();
+        for (topic, topic_data) in state.topics.iter() {
+            let mut posts = topic_data.scheduled.len() as u64;
+            let mut bytes: u64 = topic_data.scheduled.iter().map(|scheduled| scheduled.post.payload.len() as u64).sum();
+            for band in topic_data.bands.values() {
+                posts += band.posts.len() as u64;
+                bytes += band.posts.values().map(|post| post.payload.len() as u64).sum::<u64>();
+            }
+            self.topic_posts.insert(topic.clone(), posts);
+            self.topic_bytes.insert(topic.clone(), bytes);
+        }
+
+        self.subscriber_lag = state.subs.iter()
+            .map(|(sub_id, sub_data)| {
+                let lag = state.topics.get(&sub_data.topic).map_or(0, |topic_data| topic_data.lag(sub_data));
+                ((sub_id.clone(), sub_data.topic.clone()), lag)
+            })
+            .collect();
+        self.dedup_set_size = state.received_uuids.len() as u64;
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        write_header(&mut text, "broker_requests_total", "counter", "Requests received by message type");
+        for (msg_type, count) in sorted(&self.requests) {
+            writeln!(text, "broker_requests_total{{msg_type=\"{}\"}} {}", escape(msg_type), count).unwrap();
+        }
+        write_header(&mut text, "broker_request_duration_seconds_total", "counter", "Time spent handling requests by message type");
+        for (msg_type, seconds) in sorted(&self.request_seconds) {
+            writeln!(text, "broker_request_duration_seconds_total{{msg_type=\"{}\"}} {}", escape(msg_type), seconds).unwrap();
+        }
+        write_header(&mut text, "broker_errors_total", "counter", "Error replies by broker error type");
+        for (error_type, count) in sorted(&self.errors) {
+            writeln!(text, "broker_errors_total{{error_type=\"{}\"}} {}", escape(error_type), count).unwrap();
+        }
+        write_header(&mut text, "broker_topic_posts", "gauge", "Posts held by the broker per topic, scheduled ones included");
+        for (topic, posts) in sorted(&self.topic_posts) {
+            writeln!(text, "broker_topic_posts{{topic=\"{}\"}} {}", escape(topic), posts).unwrap();
+        }
+        write_header(&mut text, "broker_topic_bytes", "gauge", "Payload bytes held by the broker per topic");
+        for (topic, bytes) in sorted(&self.topic_bytes) {
+            writeln!(text, "broker_topic_bytes{{topic=\"{}\"}} {}", escape(topic), bytes).unwrap();
+        }
+        write_header(&mut text, "broker_subscriber_lag", "gauge", "Posts published to the topic that the subscriber has not read yet");
+        for ((sub_id, topic), lag) in sorted(&self.subscriber_lag) {
+            writeln!(text, "broker_subscriber_lag{{sub_id=\"{}\",topic=\"{}\"}} {}", escape(sub_id), escape(topic), lag).unwrap();
+        }
+        write_header(&mut text, "broker_dedup_set_size", "gauge", "Message uuids kept to detect duplicate puts");
+        writeln!(text, "broker_dedup_set_size {}", self.dedup_set_size).unwrap();
+        write_header(&mut text, "broker_snapshots_total", "counter", "State snapshots written");
+        writeln!(text, "broker_snapshots_total {}", self.snapshots).unwrap();
+        write_header(&mut text, "broker_snapshot_duration_seconds", "gauge", "Time taken by the last state snapshot");
+        writeln!(text, "broker_snapshot_duration_seconds {}", self.snapshot_seconds).unwrap();
+
+        text
+    }
+}
+
+fn write_header(text: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries: Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Serves the metrics over HTTP on a background thread
+// The listener is closed once a shutdown is requested, join the thread to wait for the port to be released
+pub fn serve(port: u16, metrics: Arc<Mutex<Metrics>>, shutdown: ShutdownHandle) -> Option<JoinHandle<()>> {
+    let listener = match TcpListener::bind(("127.0.0.1", port)).and_then(|listener| listener.set_nonblocking(true).map(|_| listener)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(port, %err, "Couldn't serve metrics");
+            return None;
+        }
+    };
+    info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+
+    Some(thread::spawn(move || {
+        while !shutdown.is_requested() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = stream.set_nonblocking(false).and_then(|_| handle_connection(stream, &metrics)) {
+                        error!(%err, "Couldn't answer metrics request");
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                Err(err) => error!(%err, "Couldn't accept metrics connection")
+            }
+        }
+    }))
+}
+
+fn handle_connection(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+
+    let response = if request.starts_with("GET /metrics ") || request.starts_with("GET / ") {
+        // The gauges of the previous scrape are served if the broker loop doesn't take them in time
+        metrics.lock().unwrap().state_requested = true;
+        let deadline = Instant::now() + STATE_WAIT;
+        while metrics.lock().unwrap().state_requested && Instant::now() < deadline {
+            thread::sleep(STATE_POLL);
+        }
+        let body = metrics.lock().unwrap().render();
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ Post, SubscriberData, TopicData, DEFAULT_VISIBILITY_TIMEOUT_MS, EPHEMERAL_SESSION_TIMEOUT_SECS };
+    use meic_mq::messages::subscribe::SessionType;
+    use meic_mq::messages::topic::TopicSettings;
+
+    fn post(payload: &[u8]) -> Post {
+        Post::new(payload.to_vec(), None, 0, HashMap::new(), "publisher".to_owned())
+    }
+
+    #[test]
+    fn requests_and_errors_are_counted_by_type() {
+        let mut metrics = Metrics::default();
+        metrics.observe_request("GET", Duration::from_millis(250), None);
+        metrics.observe_request("GET", Duration::from_millis(250), Some("NoPostsInTopic".to_owned()));
+        metrics.observe_request("PUT", Duration::from_millis(100), None);
+        metrics.observe_snapshot(Duration::from_millis(1500));
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE broker_requests_total counter\n"));
+        assert!(text.contains("broker_requests_total{msg_type=\"GET\"} 2\nbroker_requests_total{msg_type=\"PUT\"} 1\n"));
+        assert!(text.contains("broker_request_duration_seconds_total{msg_type=\"GET\"} 0.5\n"));
+        assert!(text.contains("broker_errors_total{error_type=\"NoPostsInTopic\"} 1\n"));
+        assert!(text.contains("broker_snapshots_total 1\n"));
+        assert!(text.contains("broker_snapshot_duration_seconds 1.5\n"));
+    }
+
+    #[test]
+    fn gauges_are_taken_from_the_state() {
+        let mut state = BrokerState::new();
+        let mut topic_data = TopicData::new(TopicSettings::default());
+        topic_data.add_post(post(b"first"));
+        topic_data.add_post(post(b"second"));
+        topic_data.schedule_post(post(b"later"), u64::MAX);
+        state.topics.insert("orders \"eu\"".to_owned(), topic_data);
+        let mut subscriber_data = SubscriberData::new("orders \"eu\"".to_owned(), HashMap::new(), SessionType::default(),
+            EPHEMERAL_SESSION_TIMEOUT_SECS, None, None, DEFAULT_VISIBILITY_TIMEOUT_MS);
+        subscriber_data.mark_read(0, 1);
+        state.subs.insert("worker".to_owned(), subscriber_data);
+        state.received_uuids.insert("uuid".to_owned());
+
+        let mut metrics = Metrics { state_requested: true, ..Metrics::default() };
+        metrics.observe_state(&state);
+        let text = metrics.render();
+
+        assert!(!metrics.state_requested());
+        assert!(text.contains("broker_topic_posts{topic=\"orders \\\"eu\\\"\"} 3\n"));
+        assert!(text.contains("broker_topic_bytes{topic=\"orders \\\"eu\\\"\"} 16\n"));
+        assert!(text.contains("broker_subscriber_lag{sub_id=\"worker\",topic=\"orders \\\"eu\\\"\"} 1\n"));
+        assert!(text.contains("broker_dedup_set_size 1\n"));
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+// Correlation ids of the signed requests accepted recently, so a captured request can't be sent again
+// while its signature timestamp is still within the tolerated clock skew
+#[derive(Debug, Default)]
+pub struct ReplayCache {
+    // Signature timestamp of each correlation id, by client id
+    seen: HashMap<String, HashMap<String, u64>>
+}
+
+impl ReplayCache {
+    pub fn remember(&mut self, client_id: &str, correlation_id: &str, timestamp_ms: u64) -> Result<(), String> {
+        let seen = self.seen.entry(client_id.to_owned()).or_default();
+        if seen.contains_key(correlation_id) {
+            return Err(format!("correlation id {} was already used by client '{}'", correlation_id, client_id));
+        }
+        seen.insert(correlation_id.to_owned(), timestamp_ms);
+        Ok(())
+    }
+
+    // Forgets the requests signed too long ago to pass the clock skew check again
+    pub fn prune(&mut self, now_ms: u64, max_clock_skew_ms: u64) {
+        let oldest_ms = now_ms.saturating_sub(max_clock_skew_ms);
+        for seen in self.seen.values_mut() {
+            seen.retain(|_, timestamp_ms| *timestamp_ms >= oldest_ms);
+        }
+        self.seen.retain(|_, seen| !seen.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+use signal_hook::consts::{ SIGINT, SIGTERM };
+use signal_hook::flag;
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+// Exit status used when a second signal interrupts the shutdown
+const FORCED_EXIT_STATUS: i32 = 130;
+
+// Asks a running broker to stop, can be cloned and used from any thread
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    // The first SIGINT or SIGTERM starts a graceful shutdown, a second one exits right away
+    pub fn listen_for_signals(&self) -> io::Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(signal, FORCED_EXIT_STATUS, self.requested.clone())?;
+            flag::register(signal, self.requested.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
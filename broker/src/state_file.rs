@@ -0,0 +1,441 @@
+use bson::{ doc, Bson, Document };
+use bson::spec::BinarySubtype;
+use meic_mq::checksum;
+use serde::{ Deserializer, Serializer };
+use serde::de::{ SeqAccess, Visitor };
+use serde_bytes::{ ByteBuf, Bytes };
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ BufReader, Write };
+
+use super::{ BrokerState, SubscriberStatus, STATE_FILE_PATH, USAGE_MESSAGE };
+
+// BSON binaries can't be larger than 16 MiB, so bigger payloads are saved as a list of chunks
+const PAYLOAD_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+// Offline commands, checked before the state file is loaded
+const COMMANDS: &[&str] = &["dump", "export", "validate", "repair"];
+
+// Runs an offline command on a state file and returns the process exit code
+pub fn run_command(arguments: &[String]) -> i32 {
+    if !COMMANDS.contains(&arguments[0].as_str()) {
+        println!("Incorrect usage of the command line interface: unknown command.");
+        println!("{}", USAGE_MESSAGE);
+        return 1;
+    }
+    let path = arguments.get(1).map_or(STATE_FILE_PATH, |path| path.as_str());
+    let mut state = match load_state(path) {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("Couldn't load state file {}: {}", path, err);
+            return 2;
+        }
+    };
+
+    match arguments[0].as_str() {
+        "dump" => {
+            dump_state(&state);
+            0
+        },
+        "export" => {
+            println!("{}", serde_json::to_string_pretty(&state).unwrap());
+            0
+        },
+        "validate" => {
+            let problems = validate_state(&state);
+            problems.iter().for_each(|problem| println!("{}", problem));
+            println!("{} problems found", problems.len());
+            if problems.is_empty() { 0 } else { 1 }
+        },
+        "repair" => {
+            let repairs = repair_state(&mut state);
+            repairs.iter().for_each(|repair| println!("{}", repair));
+            let backup_path = format!("{}.bak", path);
+            if let Err(err) = fs::copy(path, &backup_path) {
+                eprintln!("Couldn't back up {} to {}: {}", path, backup_path, err);
+                return 2;
+            }
+            if let Err(err) = write_state(&state, path) {
+                eprintln!("Couldn't write state file {}: {}", path, err);
+                return 2;
+            }
+            println!("{} repairs made, previous state kept in {}", repairs.len(), backup_path);
+            0
+        },
+        _ => unreachable!("unknown commands are refused before loading the state file")
+    }
+}
+
+// Loads a state file, upgrading snapshots written by older broker versions
+pub fn load_state(path: &str) -> Result<BrokerState, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut document = Document::from_reader(BufReader::new(file)).map_err(|err| err.to_string())?;
+    migrate_state(&mut document);
+    bson::from_document(document).map_err(|err| err.to_string())
+}
+
+pub fn serialize_payload<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if payload.len() <= PAYLOAD_CHUNK_BYTES {
+        return serializer.serialize_bytes(payload);
+    }
+    serializer.collect_seq(payload.chunks(PAYLOAD_CHUNK_BYTES).map(Bytes::new))
+}
+
+pub fn deserialize_payload<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(PayloadVisitor)
+}
+
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a binary payload or a list of binary chunks")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut chunks: A) -> Result<Vec<u8>, A::Error> {
+        let mut payload = Vec::new();
+        while let Some(chunk) = chunks.next_element::<ByteBuf>()? {
+            payload.extend_from_slice(&chunk);
+        }
+        Ok(payload)
+    }
+}
+
+fn write_state(state: &BrokerState, path: &str) -> std::io::Result<()> {
+    let state_bytes = bson::to_vec(state).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    File::create(path)?.write_all(state_bytes.as_slice())
+}
+
+fn migrate_state(document: &mut Document) {
+    if let Ok(subs) = document.get_document_mut("subs") {
+        for (_, sub) in subs.iter_mut() {
+            if let Bson::Document(sub) = sub {
+                migrate_subscriber(sub);
+            }
+        }
+    }
+    if let Ok(topics) = document.get_document_mut("topics") {
+        for (_, topic) in topics.iter_mut() {
+            if let Bson::Document(topic) = topic {
+                migrate_topic(topic);
+            }
+        }
+    }
+}
+
+// Subscribers used to keep a single last read post before priorities were introduced
+fn migrate_subscriber(sub: &mut Document) {
+    if let Some(last_read_post) = sub.remove("last_read_post") {
+        // Before delivered posts were tracked the one waiting for an ack was always the next one
+        if !sub.contains_key("delivered_post") && sub.get_str("status") == Ok("WaitingAck") {
+            let delivered_post = last_read_post.as_i64().or_else(|| last_read_post.as_i32().map(i64::from)).unwrap_or(0) + 1;
+            sub.insert("delivered_post", delivered_post);
+        }
+        if !sub.contains_key("last_read_posts") {
+            sub.insert("last_read_posts", doc! { "0": last_read_post });
+        }
+    }
+}
+
+// Topics used to keep their posts in a single band, first as raw payloads and then without headers or metadata
+fn migrate_topic(topic: &mut Document) {
+    if !topic.contains_key("bands") {
+        let posts = topic.remove("posts").unwrap_or_else(|| Bson::Document(Document::new()));
+        let post_counter = topic.remove("post_counter").unwrap_or(Bson::Int64(0));
+        topic.insert("bands", doc! { "0": { "posts": posts, "post_counter": post_counter } });
+    }
+
+    if let Ok(bands) = topic.get_document_mut("bands") {
+        for (priority, band) in bands.iter_mut() {
+            let priority: i32 = priority.parse().unwrap_or(0);
+            if let Some(Bson::Document(posts)) = band.as_document_mut().and_then(|band| band.get_mut("posts")) {
+                for (post_no, post) in posts.iter_mut() {
+                    let post_no: i64 = post_no.parse().unwrap_or(0);
+                    migrate_post(post, priority, post_no);
+                }
+            }
+        }
+    }
+
+    if let Ok(scheduled) = topic.get_array_mut("scheduled") {
+        for scheduled_post in scheduled.iter_mut() {
+            if let Some(post) = scheduled_post.as_document_mut().and_then(|scheduled_post| scheduled_post.get_mut("post")) {
+                migrate_post(post, 0, 0);
+            }
+        }
+    }
+}
+
+fn migrate_post(post: &mut Bson, priority: i32, post_no: i64) {
+    let payload = match post {
+        Bson::Document(_) => None,
+        Bson::Binary(binary) => Some(binary.bytes.clone()),
+        Bson::Array(bytes) => Some(bytes.iter().filter_map(|byte| byte.as_i32()).map(|byte| byte as u8).collect()),
+        _ => return
+    };
+    if let Some(payload) = payload {
+        *post = Bson::Document(doc! {
+            "payload": bson::Binary { subtype: BinarySubtype::Generic, bytes: payload },
+            "expires_at": Bson::Null
+        });
+    }
+
+    let post = post.as_document_mut().unwrap();
+    if !post.contains_key("priority") {
+        post.insert("priority", priority);
+    }
+    if !post.contains_key("headers") {
+        post.insert("headers", Document::new());
+    }
+    if !post.contains_key("metadata") {
+        post.insert("metadata", doc! { "pub_id": "", "received_at_ms": 0_i64, "post_no": post_no });
+    }
+}
+
+fn dump_state(state: &BrokerState) {
+    println!("Broker: {}", state.broker_uuid);
+    println!("Deduplication set: {} message uuids", state.received_uuids.len());
+
+    let mut topics: Vec<_> = state.topics.iter().collect();
+    topics.sort_by(|a, b| a.0.cmp(b.0));
+    println!("Topics: {}", topics.len());
+    for (topic, topic_data) in topics {
+        println!("  {} ({} scheduled posts)", topic, topic_data.scheduled.len());
+        let mut bands: Vec<_> = topic_data.bands.iter().collect();
+        bands.sort_by(|a, b| a.0.cmp(b.0));
+        for (priority, band) in bands {
+            let byte_size: usize = band.posts.values().map(|post| post.payload.len()).sum();
+            println!("    priority {}: post counter {}, {} retained posts, {} bytes", priority, band.post_counter, band.posts.len(), byte_size);
+        }
+    }
+
+    let mut subs: Vec<_> = state.subs.iter().collect();
+    subs.sort_by(|a, b| a.0.cmp(b.0));
+    println!("Subscribers: {}", subs.len());
+    for (sub_id, sub_data) in subs {
+        println!("  {} on {}: {:?}, {:?} session, last seen {}, last read posts {:?}, delivered post {}:{} ({} attempts)",
+            sub_id, sub_data.topic, sub_data.status, sub_data.session, sub_data.last_seen, sub_data.last_read_posts,
+            sub_data.delivered_priority, sub_data.delivered_post, sub_data.delivery_attempts);
+    }
+}
+
+fn validate_state(state: &BrokerState) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    for (topic, topic_data) in state.topics.iter() {
+        for (priority, band) in topic_data.bands.iter() {
+            if priority.parse::<u8>().is_err() {
+                problems.push(format!("Topic {} has a band with invalid priority {}", topic, priority));
+            }
+            for (post_no, post) in band.posts.iter() {
+                match post_no.parse::<u64>() {
+                    Ok(number) if number == 0 || number > band.post_counter =>
+                        problems.push(format!("Topic {} priority {} holds post {} beyond its post counter {}", topic, priority, post_no, band.post_counter)),
+                    Ok(number) if number != post.metadata.post_no =>
+                        problems.push(format!("Topic {} priority {} post {} has post number {} in its metadata", topic, priority, post_no, post.metadata.post_no)),
+                    Err(_) => problems.push(format!("Topic {} priority {} holds post with invalid number {}", topic, priority, post_no)),
+                    _ => {}
+                }
+                if post.priority.to_string() != *priority {
+                    problems.push(format!("Topic {} priority {} post {} has priority {}", topic, priority, post_no, post.priority));
+                }
+                if !checksum::verify(&post.payload, post.checksum) {
+                    problems.push(format!("Topic {} priority {} post {} doesn't match its checksum", topic, priority, post_no));
+                }
+            }
+        }
+        for scheduled_post in topic_data.scheduled.iter() {
+            if !checksum::verify(&scheduled_post.post.payload, scheduled_post.post.checksum) {
+                problems.push(format!("Topic {} post scheduled for {} doesn't match its checksum", topic, scheduled_post.deliver_at_ms));
+            }
+        }
+    }
+
+    for (sub_id, sub_data) in state.subs.iter() {
+        let topic_data = match state.topics.get(&sub_data.topic) {
+            Some(topic_data) => topic_data,
+            None => {
+                problems.push(format!("Subscriber {} is subscribed to inexistant topic {}", sub_id, sub_data.topic));
+                continue;
+            }
+        };
+        for (priority, last_read_post) in sub_data.last_read_posts.iter() {
+            let post_counter = topic_data.bands.get(priority).map_or(0, |band| band.post_counter);
+            if *last_read_post > post_counter {
+                problems.push(format!("Subscriber {} last read post {} of priority {} is beyond its post counter {}", sub_id, last_read_post, priority, post_counter));
+            }
+        }
+        if let SubscriberStatus::WaitingAck = sub_data.status {
+            let post_counter = topic_data.bands.get(&sub_data.delivered_priority.to_string()).map_or(0, |band| band.post_counter);
+            if sub_data.delivered_post == 0 || sub_data.delivered_post > post_counter {
+                problems.push(format!("Subscriber {} waits for the ack of post {}:{} that was never published", sub_id, sub_data.delivered_priority, sub_data.delivered_post));
+            }
+        }
+    }
+
+    problems
+}
+
+fn repair_state(state: &mut BrokerState) -> Vec<String> {
+    let mut repairs: Vec<String> = Vec::new();
+
+    for (topic, topic_data) in state.topics.iter_mut() {
+        topic_data.bands.retain(|priority, _| {
+            let valid = priority.parse::<u8>().is_ok();
+            if !valid {
+                repairs.push(format!("Removed band with invalid priority {} from topic {}", priority, topic));
+            }
+            valid
+        });
+        for (priority, band) in topic_data.bands.iter_mut() {
+            let post_counter = band.post_counter;
+            band.posts.retain(|post_no, _| {
+                let valid = matches!(post_no.parse::<u64>(), Ok(number) if number > 0 && number <= post_counter);
+                if !valid {
+                    repairs.push(format!("Removed post {} beyond the post counter of topic {} priority {}", post_no, topic, priority));
+                }
+                valid
+            });
+            for (post_no, post) in band.posts.iter_mut() {
+                let number: u64 = post_no.parse().unwrap();
+                if post.metadata.post_no != number || post.priority.to_string() != *priority {
+                    post.metadata.post_no = number;
+                    post.priority = priority.parse().unwrap();
+                    repairs.push(format!("Fixed the metadata of post {} of topic {} priority {}", post_no, topic, priority));
+                }
+            }
+        }
+    }
+
+    for post in remove_corrupted_posts(state) {
+        repairs.push(format!("Removed corrupted post {}", post));
+    }
+
+    let topics = &state.topics;
+    state.subs.retain(|sub_id, sub_data| {
+        let valid = topics.contains_key(&sub_data.topic);
+        if !valid {
+            repairs.push(format!("Removed subscriber {} of inexistant topic {}", sub_id, sub_data.topic));
+        }
+        valid
+    });
+
+    for (sub_id, sub_data) in state.subs.iter_mut() {
+        let topic_data = &state.topics[&sub_data.topic];
+        let post_counters: HashMap<&String, u64> = topic_data.bands.iter().map(|(priority, band)| (priority, band.post_counter)).collect();
+        for (priority, last_read_post) in sub_data.last_read_posts.iter_mut() {
+            let post_counter = *post_counters.get(priority).unwrap_or(&0);
+            if *last_read_post > post_counter {
+                repairs.push(format!("Moved subscriber {} last read post of priority {} back from {} to {}", sub_id, priority, last_read_post, post_counter));
+                *last_read_post = post_counter;
+            }
+        }
+        if let SubscriberStatus::WaitingAck = sub_data.status {
+            let post_counter = *post_counters.get(&sub_data.delivered_priority.to_string()).unwrap_or(&0);
+            if sub_data.delivered_post == 0 || sub_data.delivered_post > post_counter {
+                repairs.push(format!("Stopped subscriber {} from waiting for the ack of post {}:{}", sub_id, sub_data.delivered_priority, sub_data.delivered_post));
+                sub_data.change_status(SubscriberStatus::WaitingGet);
+                sub_data.delivered_post = 0;
+                sub_data.delivery_attempts = 0;
+                sub_data.redeliver_at_ms = 0;
+            }
+        }
+    }
+
+    repairs
+}
+
+// Removes the posts that don't match their checksum and describes each one
+pub fn remove_corrupted_posts(state: &mut BrokerState) -> Vec<String> {
+    let mut removed: Vec<String> = Vec::new();
+
+    for (topic, topic_data) in state.topics.iter_mut() {
+        for (priority, band) in topic_data.bands.iter_mut() {
+            band.posts.retain(|post_no, post| {
+                let valid = checksum::verify(&post.payload, post.checksum);
+                if !valid {
+                    removed.push(format!("topic={} priority={} post_no={}", topic, priority, post_no));
+                }
+                valid
+            });
+        }
+        topic_data.scheduled.retain(|scheduled_post| {
+            let valid = checksum::verify(&scheduled_post.post.payload, scheduled_post.post.checksum);
+            if !valid {
+                removed.push(format!("topic={} deliver_at_ms={}", topic, scheduled_post.deliver_at_ms));
+            }
+            valid
+        });
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Post, TopicData };
+    use meic_mq::messages::topic::TopicSettings;
+
+    fn state_with_posts(payloads: Vec<Vec<u8>>) -> BrokerState {
+        let mut topic_data = TopicData::new(TopicSettings::default());
+        for payload in payloads {
+            topic_data.add_post(Post::new(payload, None, 0, HashMap::new(), "publisher".to_owned()));
+        }
+        let mut state = BrokerState::new();
+        state.topics.insert("orders".to_owned(), topic_data);
+        state
+    }
+
+    #[test]
+    fn payloads_larger_than_a_chunk_round_trip() {
+        let payload: Vec<u8> = (0..PAYLOAD_CHUNK_BYTES * 2 + 1).map(|index| index as u8).collect();
+        let state = state_with_posts(vec![payload.clone(), b"small".to_vec()]);
+
+        let document = bson::to_document(&state).unwrap();
+        let saved_payload = |post_no: &str| document.get_document("topics").unwrap().get_document("orders").unwrap()
+            .get_document("bands").unwrap().get_document("0").unwrap()
+            .get_document("posts").unwrap().get_document(post_no).unwrap().get("payload").unwrap().clone();
+        assert_eq!(saved_payload("1").as_array().map(Vec::len), Some(3));
+        assert!(matches!(saved_payload("2"), Bson::Binary(_)));
+
+        let path = std::env::temp_dir().join(format!("state_file_chunks_{}.bson", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_state(&state, path).unwrap();
+        let loaded = load_state(path);
+        fs::remove_file(path).unwrap();
+        let loaded = loaded.unwrap();
+        let band = &loaded.topics["orders"].bands["0"];
+        assert_eq!(band.posts["1"].payload, payload);
+        assert_eq!(band.posts["2"].payload, b"small");
+        assert!(validate_state(&loaded).is_empty());
+    }
+
+    #[test]
+    fn repair_drops_corrupted_posts() {
+        let mut state = state_with_posts(vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+        state.topics.get_mut("orders").unwrap().bands.get_mut("0").unwrap().posts.get_mut("2").unwrap().payload[0] ^= 1;
+        assert_eq!(validate_state(&state).len(), 1);
+
+        let repairs = repair_state(&mut state);
+        assert_eq!(repairs, vec!["Removed corrupted post topic=orders priority=0 post_no=2".to_owned()]);
+        let band = &state.topics["orders"].bands["0"];
+        let mut post_nos: Vec<&String> = band.posts.keys().collect();
+        post_nos.sort();
+        assert_eq!(post_nos, ["1", "3"]);
+        assert_eq!(band.post_counter, 3);
+        assert!(validate_state(&state).is_empty());
+    }
+}
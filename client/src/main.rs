@@ -1,5 +1,6 @@
 use std::env;
 use std::process;
+use tracing_subscriber::EnvFilter;
 
 mod slow_subscriber_scenario;
 mod late_sub_scen;
@@ -10,6 +11,16 @@ conc_sub_cars, conc_sub_bio, conc_pub_cars, conc_pub_bio].";
 
 fn main() {
 
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    // Credentials and CURVE keys are taken from the environment
+    if let Err(err) = meic_mq::configure_from_env() {
+        eprintln!("Couldn't set up the connection to the broker: {}", err);
+        process::exit(1);
+    }
+
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
@@ -0,0 +1,144 @@
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+use std::env;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+// Signed requests start with this frame, followed by the client id, the timestamp and the signature
+pub const AUTH_FRAME: &[u8] = b"auth";
+
+// Environment variables the client binaries read their credentials from
+pub const CLIENT_ID_VAR: &str = "MEIC_CLIENT_ID";
+pub const CLIENT_SECRET_VAR: &str = "MEIC_CLIENT_SECRET";
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Client id and shared secret configured on the broker, used to sign every request
+#[derive(Clone)]
+pub struct Credentials {
+    pub client_id: String,
+    secret: String
+}
+
+impl Credentials {
+    pub fn new(client_id: String, secret: String) -> Credentials {
+        Credentials {
+            client_id,
+            secret
+        }
+    }
+
+    pub fn from_env() -> Option<Credentials> {
+        match (env::var(CLIENT_ID_VAR), env::var(CLIENT_SECRET_VAR)) {
+            (Ok(client_id), Ok(secret)) => Some(Credentials::new(client_id, secret)),
+            _ => None
+        }
+    }
+
+    // Prepends the authentication frames to the frames of a request
+    pub fn sign(&self, frames: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let signature = mac(&self.secret, &self.client_id, timestamp_ms, &frames).finalize().into_bytes().to_vec();
+        let mut signed_frames = vec![AUTH_FRAME.to_vec(), self.client_id.as_bytes().to_vec(), timestamp_ms.to_string().into_bytes(), signature];
+        signed_frames.extend(frames);
+        signed_frames
+    }
+}
+
+// Authentication frames of a request, verified by the broker with the secret of the client
+pub struct Signature {
+    pub client_id: String,
+    pub timestamp_ms: u64,
+    signature: Vec<u8>
+}
+
+impl Signature {
+    // Separates the authentication frames from the frames of the message, unsigned requests have none
+    pub fn split(frames: Vec<Vec<u8>>) -> Result<(Option<Signature>, Vec<Vec<u8>>), String> {
+        if frames.first().map(Vec::as_slice) != Some(AUTH_FRAME) {
+            return Ok((None, frames));
+        }
+        if frames.len() < 5 {
+            return Err(format!("expected the authentication frames and the message, received {} frames", frames.len()));
+        }
+        let mut frames = frames.into_iter().skip(1);
+        let client_id = String::from_utf8(frames.next().unwrap()).map_err(|_| "the client id is not valid UTF-8".to_owned())?;
+        let timestamp_ms = String::from_utf8_lossy(&frames.next().unwrap()).parse().map_err(|_| "invalid signature timestamp".to_owned())?;
+        let signature = frames.next().unwrap();
+        Ok((Some(Signature { client_id, timestamp_ms, signature }), frames.collect()))
+    }
+
+    pub fn verify(&self, secret: &str, frames: &[Vec<u8>]) -> bool {
+        mac(secret, &self.client_id, self.timestamp_ms, frames).verify_slice(&self.signature).is_ok()
+    }
+}
+
+// Every part is prefixed with its length so moving bytes between frames changes the signature
+fn mac(secret: &str, client_id: &str, timestamp_ms: u64, frames: &[Vec<u8>]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    let timestamp = timestamp_ms.to_string();
+    let parts = [client_id.as_bytes(), timestamp.as_bytes()].into_iter().chain(frames.iter().map(Vec::as_slice));
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_frames() -> Vec<Vec<u8>> {
+        vec![b"bson".to_vec(), b"message".to_vec(), b"payload".to_vec()]
+    }
+
+    fn signed_request() -> (Signature, Vec<Vec<u8>>) {
+        let signed_frames = Credentials::new("alice".to_owned(), "secret".to_owned()).sign(request_frames());
+        let (signature, frames) = Signature::split(signed_frames).unwrap();
+        (signature.unwrap(), frames)
+    }
+
+    #[test]
+    fn signed_request_round_trips() {
+        let (signature, frames) = signed_request();
+        assert_eq!(signature.client_id, "alice");
+        assert_eq!(frames, request_frames());
+        assert!(signature.verify("secret", &frames));
+    }
+
+    #[test]
+    fn tampered_frames_are_refused() {
+        let (signature, mut frames) = signed_request();
+        frames[2][0] ^= 1;
+        assert!(!signature.verify("secret", &frames));
+
+        // Same bytes, split differently between the frames
+        let (signature, _) = signed_request();
+        let moved = vec![b"bson".to_vec(), b"messagep".to_vec(), b"ayload".to_vec()];
+        assert!(!signature.verify("secret", &moved));
+    }
+
+    #[test]
+    fn wrong_secret_is_refused() {
+        let (signature, frames) = signed_request();
+        assert!(!signature.verify("other secret", &frames));
+    }
+
+    #[test]
+    fn changed_timestamp_is_refused() {
+        let (mut signature, frames) = signed_request();
+        signature.timestamp_ms += 1;
+        assert!(!signature.verify("secret", &frames));
+    }
+
+    #[test]
+    fn unsigned_and_truncated_requests_are_split() {
+        let (signature, frames) = Signature::split(request_frames()).unwrap();
+        assert!(signature.is_none());
+        assert_eq!(frames, request_frames());
+
+        let truncated = vec![AUTH_FRAME.to_vec(), b"alice".to_vec(), b"0".to_vec(), b"signature".to_vec()];
+        assert!(Signature::split(truncated).is_err());
+    }
+}
@@ -0,0 +1,9 @@
+// CRC32C of a payload as it is sent and stored, after any compression
+pub fn compute(payload: &[u8]) -> u32 {
+    crc32c::crc32c(payload)
+}
+
+// Payloads without a checksum, sent by older clients or stored by older brokers, can't be verified
+pub fn verify(payload: &[u8], checksum: Option<u32>) -> bool {
+    checksum.is_none_or(|checksum| compute(payload) == checksum)
+}
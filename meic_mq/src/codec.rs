@@ -0,0 +1,263 @@
+use crate::messages::Message;
+
+// Name of the codec used by requests made of a single frame
+pub const DEFAULT_CODEC: &str = "bson";
+
+// Turns messages into bytes and back, payloads keep their BSON value model in every codec
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, String>;
+    fn decode(&self, bytes: &[u8]) -> Result<Message, String>;
+}
+
+pub struct BsonCodec;
+
+impl Codec for BsonCodec {
+    fn name(&self) -> &'static str {
+        "bson"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, String> {
+        bson::to_vec(message).map_err(|err| err.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Message, String> {
+        bson::from_slice(bytes).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(message).map_err(|err| err.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Message, String> {
+        rmp_serde::from_slice(bytes).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(message, &mut bytes).map_err(|err| err.to_string())?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Message, String> {
+        ciborium::de::from_reader(bytes).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(message).map_err(|err| err.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Message, String> {
+        serde_json::from_slice(bytes).map_err(|err| err.to_string())
+    }
+}
+
+// Codecs compiled into this build, BSON first
+pub fn codecs() -> Vec<&'static dyn Codec> {
+    vec![
+        &BsonCodec as &'static dyn Codec,
+        #[cfg(feature = "msgpack")]
+        &MessagePackCodec,
+        #[cfg(feature = "cbor")]
+        &CborCodec,
+        #[cfg(feature = "json")]
+        &JsonCodec
+    ]
+}
+
+pub fn codec_by_name(name: &str) -> Option<&'static dyn Codec> {
+    codecs().into_iter().find(|codec| codec.name() == name)
+}
+
+// BSON messages are sent as a single frame, as before codecs existed, others as [codec name, body].
+// A message with a payload frame is always sent as [codec name, body, payload]
+pub fn encode_frames(codec: &dyn Codec, mut message: Message) -> Result<Vec<Vec<u8>>, String> {
+    let payload_frame = message.payload_frame.take();
+    let body = codec.encode(&message)?;
+    match payload_frame {
+        Some(payload) => Ok(vec![codec.name().as_bytes().to_vec(), body, payload]),
+        None if codec.name() == DEFAULT_CODEC => Ok(vec![body]),
+        None => Ok(vec![codec.name().as_bytes().to_vec(), body])
+    }
+}
+
+pub fn decode_frames(frames: Vec<Vec<u8>>) -> Result<(&'static dyn Codec, Message), String> {
+    let frame_count = frames.len();
+    let mut frames = frames.into_iter();
+    let (codec, body, payload_frame) = match (frames.next(), frames.next(), frames.next(), frames.next()) {
+        (Some(body), None, None, None) => (&BsonCodec as &'static dyn Codec, body, None),
+        (Some(codec_name), Some(body), payload_frame, None) => {
+            let codec_name = String::from_utf8_lossy(&codec_name);
+            let codec = codec_by_name(&codec_name).ok_or(format!("unsupported codec '{}'", codec_name))?;
+            (codec, body, payload_frame)
+        },
+        _ => return Err(format!("expected 1 to 3 frames, received {}", frame_count))
+    };
+    let mut message = codec.decode(&body).map_err(|err| format!("invalid {} message envelope: {}", codec.name(), err))?;
+    message.payload_frame = payload_frame;
+    Ok((codec, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+    use crate::compression::{ Compression, DEFAULT_COMPRESSION_THRESHOLD_BYTES };
+    use crate::messages::{ put, NetworkTradeable };
+
+    // Encodes a put request with its payload in its own frame and one embedded in the message, and decodes them back
+    fn assert_round_trip(codec: &'static dyn Codec) {
+        let mut request = put::Request::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec());
+        request.headers.insert("key".to_owned(), "value".to_owned());
+        request.priority = 3;
+        let framed = request.control_header().as_message().with_payload_frame(request.payload.clone());
+        let embedded = request.as_message();
+
+        for (message, payload_frame) in [(framed, Some(request.payload.clone())), (embedded, None)] {
+            let correlation_id = message.correlation_id.clone();
+            let frames = encode_frames(codec, message).unwrap();
+            let (decoded_codec, decoded) = decode_frames(frames).unwrap();
+            assert_eq!(decoded_codec.name(), codec.name());
+            assert_eq!(decoded.correlation_id, correlation_id);
+            assert_eq!(decoded.payload_frame, payload_frame);
+
+            let payload = decoded.payload_frame.clone();
+            let decoded = put::Request::from_message(decoded).ok().unwrap();
+            assert_eq!(decoded.message_uuid, request.message_uuid);
+            assert_eq!(decoded.topic, request.topic);
+            assert_eq!(decoded.headers, request.headers);
+            assert_eq!(decoded.priority, request.priority);
+            assert_eq!(decoded.checksum, request.checksum);
+            assert_eq!(payload.unwrap_or(decoded.payload), request.payload);
+        }
+    }
+
+    #[test]
+    fn bson_round_trips() {
+        assert_round_trip(&BsonCodec);
+    }
+
+    #[test]
+    fn bson_without_payload_frame_is_a_single_frame() {
+        let request = put::Request::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec());
+        assert_eq!(encode_frames(&BsonCodec, request.as_message()).unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        assert_round_trip(&MessagePackCodec);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips() {
+        assert_round_trip(&CborCodec);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trips() {
+        assert_round_trip(&JsonCodec);
+    }
+
+    #[test]
+    fn unknown_codec_is_refused() {
+        let err = decode_frames(vec![b"yaml".to_vec(), b"topic: orders".to_vec()]).err().unwrap();
+        assert_eq!(err, "unsupported codec 'yaml'");
+    }
+
+    #[test]
+    fn wrong_frame_count_is_refused() {
+        assert!(decode_frames(Vec::new()).is_err());
+        assert!(decode_frames(vec![b"bson".to_vec(); 4]).is_err());
+    }
+
+    // Compresses a put request and checks its payload comes back the same in its own frame with every codec
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4", feature = "gzip")), allow(dead_code))]
+    fn assert_compressed_round_trip(compression: Compression) {
+        let payload = b"order ".repeat(1000);
+        let request = put::Request::new("publisher".to_owned(), "orders".to_owned(), payload.clone())
+            .with_compression(compression, DEFAULT_COMPRESSION_THRESHOLD_BYTES).unwrap();
+        assert_eq!(request.compression, Some(compression));
+        assert!(request.payload.len() < payload.len());
+
+        for codec in codecs() {
+            let message = request.control_header().as_message().with_payload_frame(request.payload.clone());
+            let (_, decoded) = decode_frames(encode_frames(codec, message).unwrap()).unwrap();
+            let compressed = decoded.payload_frame.clone().unwrap();
+            let decoded = put::Request::from_message(decoded).ok().unwrap();
+            assert_eq!(decoded.compression, Some(compression));
+            assert!(checksum::verify(&compressed, decoded.checksum));
+            assert_eq!(compression.decompress(&compressed).unwrap(), payload);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_payloads_round_trip() {
+        assert_compressed_round_trip(Compression::Zstd);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_payloads_round_trip() {
+        assert_compressed_round_trip(Compression::Lz4);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_payloads_round_trip() {
+        assert_compressed_round_trip(Compression::Gzip);
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let request = put::Request::new("publisher".to_owned(), "orders".to_owned(), b"payload".to_vec());
+        let checksum = request.checksum;
+        let request = request.with_compression(Compression::Gzip, DEFAULT_COMPRESSION_THRESHOLD_BYTES).unwrap();
+        assert_eq!(request.compression, None);
+        assert_eq!(request.payload, b"payload");
+        assert_eq!(request.checksum, checksum);
+    }
+
+    #[test]
+    fn unavailable_compressions_are_refused() {
+        for compression in [Compression::Zstd, Compression::Lz4, Compression::Gzip] {
+            if !compression.is_available() {
+                assert!(compression.compress(b"payload").is_err());
+                assert!(compression.decompress(b"payload").is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use serde::{Serialize, Deserialize};
+
+// Payloads smaller than this are usually not worth compressing
+pub const DEFAULT_COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
+// Algorithm a payload was compressed with, each one is only available when its cargo feature is enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+    Gzip
+}
+
+impl Compression {
+    pub fn is_available(&self) -> bool {
+        match self {
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Gzip => cfg!(feature = "gzip")
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4", feature = "gzip")), allow(unused_variables))]
+    pub fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(payload, 0).map_err(|err| err.to_string()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload).and_then(|_| encoder.finish()).map_err(|err| err.to_string())
+            },
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable())
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4", feature = "gzip")), allow(unused_variables))]
+    pub fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::decode_all(payload).map_err(|err| err.to_string()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(payload).map_err(|err| err.to_string()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Read;
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(payload).read_to_end(&mut decompressed).map_err(|err| err.to_string())?;
+                Ok(decompressed)
+            },
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable())
+        }
+    }
+
+    fn unavailable(&self) -> String {
+        format!("{:?} compression is not available in this build", self)
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use tracing::error;
 
 use super::{ FileWritable, ContextIOError, read };
 use super::super::messages::put;
@@ -21,10 +22,7 @@ impl PublisherContext {
 
     pub fn read(pub_id: String) -> Result<PublisherContext, ContextIOError> {
         let path: String = format!("{}{}", PUB_STORAGE_PATH, pub_id);
-        let mut pub_ctx: PublisherContext = match super::read(path) {
-            Ok(val) => val,
-            Err(err) => return Err(err)
-        };
+        let mut pub_ctx: PublisherContext = super::read(path)?;
         pub_ctx.pub_id = pub_id;
         Ok(pub_ctx)
     }
@@ -33,6 +31,14 @@ impl PublisherContext {
         put::Request::new(self.pub_id.clone(), topic, payload)
     }
 
+    pub fn create_put_request_with_ttl(&self, topic: String, payload: Vec<u8>, ttl_secs: u64) -> put::Request {
+        put::Request::new(self.pub_id.clone(), topic, payload).with_ttl(ttl_secs)
+    }
+
+    pub fn create_delayed_put_request(&self, topic: String, payload: Vec<u8>, delay_ms: u64) -> put::Request {
+        put::Request::new(self.pub_id.clone(), topic, payload).with_delay(delay_ms)
+    }
+
     pub fn from_file(id: &String) -> Result<PublisherContext, ContextIOError> {
         read(format!("{}{}.bson", Self::build_prefix(), id))
     }
@@ -40,15 +46,15 @@ impl PublisherContext {
 
 impl FileWritable<PublisherContext> for PublisherContext {
     fn get_prefix(&self) -> &'static str {
-        return PUB_STORAGE_PATH;
+        PUB_STORAGE_PATH
     }
 
     fn build_path(&self) -> String {
-        return format!("{}{}.bson", PUB_STORAGE_PATH, self.pub_id);
+        format!("{}{}.bson", PUB_STORAGE_PATH, self.pub_id)
     }
 
     fn build_prefix() -> &'static str {
-        return PUB_STORAGE_PATH;
+        PUB_STORAGE_PATH
     }
 }
 
@@ -56,9 +62,9 @@ impl Drop for PublisherContext {
     fn drop(&mut self) {
         if let Err(err) = super::save(self) {
             match err {
-                ContextIOError::ErrorCreatingDirectory(err) => eprintln!("Couldn't create directory to save {} publisher's context: {}", self.pub_id, err),
-                ContextIOError::ErrorWritingToFile(err) => eprintln!("Couldn't write to {} publisher's context: {}", self.pub_id, err),
-                _ => eprintln!("Unexpected error while writing {} publisher's state", self.pub_id)
+                ContextIOError::ErrorCreatingDirectory(err) => error!("Couldn't create directory to save {} publisher's context: {}", self.pub_id, err),
+                ContextIOError::ErrorWritingToFile(err) => error!("Couldn't write to {} publisher's context: {}", self.pub_id, err),
+                _ => error!("Unexpected error while writing {} publisher's state", self.pub_id)
             }
         }
     }
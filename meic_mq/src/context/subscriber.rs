@@ -1,16 +1,74 @@
 use serde::{Serialize, Deserialize};
+use tracing::error;
+use std::collections::HashMap;
 
 use super::{ ContextIOError, FileWritable, read };
-use super::super::messages::{ get, unsubscribe, subscribe };
+use super::super::messages::{ get, unsubscribe, subscribe, subscribe::SessionType };
 
 const SUB_STORAGE_PATH: &str = "./data/sub/";
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "StoredSubscriberContext")]
 pub struct SubscriberContext {
     pub sub_id: String,
     pub topic: String,
     pub known_broker_id: Option<String>,
-    pub next_post_no: u64
+    // Next post number to read for each priority
+    #[serde(default)]
+    pub next_post_nos: HashMap<String, u64>,
+    #[serde(default)]
+    pub session: SessionType,
+    #[serde(default)]
+    pub session_timeout: Option<u64>,
+    #[serde(default)]
+    pub max_delivery_attempts: Option<u32>,
+    #[serde(default)]
+    pub dead_letter_topic: Option<String>,
+    #[serde(default)]
+    pub visibility_timeout_ms: Option<u64>
+}
+
+// Context as saved on disk, including the single post number of the contexts saved before priorities
+#[derive(Deserialize)]
+struct StoredSubscriberContext {
+    sub_id: String,
+    topic: String,
+    known_broker_id: Option<String>,
+    #[serde(default)]
+    next_post_no: Option<u64>,
+    #[serde(default)]
+    next_post_nos: HashMap<String, u64>,
+    #[serde(default)]
+    session: SessionType,
+    #[serde(default)]
+    session_timeout: Option<u64>,
+    #[serde(default)]
+    max_delivery_attempts: Option<u32>,
+    #[serde(default)]
+    dead_letter_topic: Option<String>,
+    #[serde(default)]
+    visibility_timeout_ms: Option<u64>
+}
+
+impl From<StoredSubscriberContext> for SubscriberContext {
+    fn from(stored: StoredSubscriberContext) -> SubscriberContext {
+        let mut next_post_nos = stored.next_post_nos;
+        // Posts read before priorities were all in the default band
+        if let Some(next_post_no) = stored.next_post_no {
+            next_post_nos.entry(0.to_string()).or_insert(next_post_no);
+        }
+        SubscriberContext {
+            sub_id: stored.sub_id,
+            topic: stored.topic,
+            known_broker_id: stored.known_broker_id,
+            next_post_nos,
+            session: stored.session,
+            session_timeout: stored.session_timeout,
+            max_delivery_attempts: stored.max_delivery_attempts,
+            dead_letter_topic: stored.dead_letter_topic,
+            visibility_timeout_ms: stored.visibility_timeout_ms
+        }
+    }
 }
 
 impl SubscriberContext {
@@ -19,20 +77,50 @@ impl SubscriberContext {
             sub_id,
             topic,
             known_broker_id: None,
-            next_post_no: 1
+            next_post_nos: HashMap::new(),
+            session: SessionType::Durable,
+            session_timeout: None,
+            max_delivery_attempts: None,
+            dead_letter_topic: None,
+            visibility_timeout_ms: None
+        }
+    }
+
+    pub fn new_ephemeral(sub_id: String, topic: String, session_timeout: Option<u64>) -> SubscriberContext {
+        SubscriberContext {
+            sub_id,
+            topic,
+            known_broker_id: None,
+            next_post_nos: HashMap::new(),
+            session: SessionType::Ephemeral,
+            session_timeout,
+            max_delivery_attempts: None,
+            dead_letter_topic: None,
+            visibility_timeout_ms: None
         }
     }
 
-    pub fn increment_next_post_no(&mut self) {
-        self.next_post_no += 1
+    pub fn next_post_no(&self, priority: u8) -> u64 {
+        *self.next_post_nos.get(&priority.to_string()).unwrap_or(&1)
+    }
+
+    pub fn increment_next_post_no(&mut self, priority: u8) {
+        self.skip_posts(priority, 1)
+    }
+
+    pub fn skip_posts(&mut self, priority: u8, skipped: u64) {
+        let next_post_no = self.next_post_no(priority) + skipped;
+        self.next_post_nos.insert(priority.to_string(), next_post_no);
+    }
+
+    pub fn set_dead_letter_policy(&mut self, max_delivery_attempts: u32, dead_letter_topic: Option<String>) {
+        self.max_delivery_attempts = Some(max_delivery_attempts);
+        self.dead_letter_topic = dead_letter_topic;
     }
 
     pub fn read(sub_id: String) -> Result<SubscriberContext, ContextIOError> {
         let path: String = format!("{}{}", SUB_STORAGE_PATH, sub_id);
-        let mut sub_ctx: SubscriberContext = match super::read(path) {
-            Ok(val) => val,
-            Err(err) => return Err(err)
-        };
+        let mut sub_ctx: SubscriberContext = super::read(path)?;
         sub_ctx.sub_id = sub_id;
         Ok(sub_ctx)
     }
@@ -42,7 +130,14 @@ impl SubscriberContext {
     }
 
     pub fn create_subscribe_request(&self) -> subscribe::Request {
-        subscribe::Request::new(self.sub_id.clone(), self.topic.clone())
+        let mut request = subscribe::Request::with_session(self.sub_id.clone(), self.topic.clone(), self.session, self.session_timeout);
+        if let Some(max_delivery_attempts) = self.max_delivery_attempts {
+            request = request.with_dead_letter(max_delivery_attempts, self.dead_letter_topic.clone());
+        }
+        if let Some(visibility_timeout_ms) = self.visibility_timeout_ms {
+            request = request.with_visibility_timeout(visibility_timeout_ms);
+        }
+        request
     }
 
     pub fn create_unsubscribe_request(&self) -> unsubscribe::Request {
@@ -56,15 +151,15 @@ impl SubscriberContext {
 
 impl FileWritable<SubscriberContext> for SubscriberContext {
     fn get_prefix(&self) -> &'static str {
-        return SUB_STORAGE_PATH;
+        SUB_STORAGE_PATH
     }
 
     fn build_path(&self) -> String {
-        return format!("{}{}.bson", SUB_STORAGE_PATH, self.sub_id);
+        format!("{}{}.bson", SUB_STORAGE_PATH, self.sub_id)
     }
     
     fn build_prefix() -> &'static str {
-        return SUB_STORAGE_PATH;
+        SUB_STORAGE_PATH
     }
 }
 
@@ -72,12 +167,31 @@ impl Drop for SubscriberContext {
     fn drop(&mut self) {
         if let Err(err) = super::save(self) {
             match err {
-                ContextIOError::ErrorCreatingDirectory(err) => eprintln!("Couldn't create directory to save {} subscriber's context: {}", self.sub_id, err),
-                ContextIOError::ErrorWritingToFile(err) => eprintln!("Couldn't write to {} subscriber's context: {}", self.sub_id, err),
-                _ => eprintln!("Unexpected error while writing {} subscriber's state", self.sub_id)
+                ContextIOError::ErrorCreatingDirectory(err) => error!("Couldn't create directory to save {} subscriber's context: {}", self.sub_id, err),
+                ContextIOError::ErrorWritingToFile(err) => error!("Couldn't write to {} subscriber's context: {}", self.sub_id, err),
+                _ => error!("Unexpected error while writing {} subscriber's state", self.sub_id)
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
 
+    #[test]
+    fn contexts_saved_before_priorities_keep_their_position() {
+        let saved = bson::doc! { "sub_id": "worker", "topic": "orders", "known_broker_id": "broker", "next_post_no": 5_i64 };
+        // Dropping a context saves it to the working directory
+        let context = ManuallyDrop::new(bson::from_document::<SubscriberContext>(saved).unwrap());
+        assert_eq!(context.next_post_no(0), 5);
+        assert_eq!(context.next_post_no(9), 1);
+        assert_eq!(context.session, SessionType::Durable);
+
+        let resaved = bson::to_document(&*context).unwrap();
+        assert!(!resaved.contains_key("next_post_no"));
+        let context = ManuallyDrop::new(bson::from_document::<SubscriberContext>(resaved).unwrap());
+        assert_eq!(context.next_post_no(0), 5);
+    }
+}
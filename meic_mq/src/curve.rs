@@ -0,0 +1,106 @@
+use std::env;
+
+use super::ffi;
+
+// Environment variables the client binaries read their CURVE keys from
+pub const SERVER_KEY_VAR: &str = "MEIC_SERVER_KEY";
+pub const PUBLIC_KEY_VAR: &str = "MEIC_CURVE_PUBLIC_KEY";
+pub const SECRET_KEY_VAR: &str = "MEIC_CURVE_SECRET_KEY";
+
+// Keys are 32 bytes, written as 40 Z85 characters
+const KEY_LEN: usize = 32;
+
+// Whether the linked libzmq was built with CURVE support
+pub fn is_available() -> bool {
+    zmq::has("curve") == Some(true)
+}
+
+// CURVE key pair of the broker or of a client, kept in its Z85 form
+#[derive(Clone)]
+pub struct KeyPair {
+    pub public_key: String,
+    secret_key: String
+}
+
+impl KeyPair {
+    pub fn new(public_key: String, secret_key: String) -> Result<KeyPair, String> {
+        decode_key(&public_key).map_err(|err| format!("Invalid CURVE public key: {}", err))?;
+        decode_key(&secret_key).map_err(|err| format!("Invalid CURVE secret key: {}", err))?;
+        if public_key_of(&secret_key)? != public_key {
+            return Err("The CURVE public key doesn't belong to the secret key".to_owned());
+        }
+        Ok(KeyPair { public_key, secret_key })
+    }
+
+    pub fn generate() -> Result<KeyPair, String> {
+        check_available()?;
+        let pair = zmq::CurveKeyPair::new().map_err(|err| format!("Couldn't generate a CURVE key pair: {}", err))?;
+        Ok(KeyPair {
+            public_key: zmq::z85_encode(&pair.public_key).unwrap(),
+            secret_key: zmq::z85_encode(&pair.secret_key).unwrap()
+        })
+    }
+
+    pub fn secret_key(&self) -> &str {
+        &self.secret_key
+    }
+
+    // Makes the socket accept only CURVE connections, as the broker
+    pub fn configure_server(&self, socket: &zmq::Socket) -> Result<(), String> {
+        check_available()?;
+        socket.set_curve_server(true).map_err(|err| err.to_string())?;
+        socket.set_curve_secretkey(&decode_key(&self.secret_key)?).map_err(|err| err.to_string())
+    }
+}
+
+// Keys a client needs to open an encrypted connection to the broker
+#[derive(Clone)]
+pub struct ClientKeys {
+    pub server_key: String,
+    pub keypair: KeyPair
+}
+
+impl ClientKeys {
+    pub fn new(server_key: String, keypair: KeyPair) -> Result<ClientKeys, String> {
+        decode_key(&server_key).map_err(|err| format!("Invalid CURVE server key: {}", err))?;
+        Ok(ClientKeys { server_key, keypair })
+    }
+
+    // Without a client key pair a new one is generated, the broker doesn't tell clients apart by their keys
+    pub fn from_env() -> Result<Option<ClientKeys>, String> {
+        let Ok(server_key) = env::var(SERVER_KEY_VAR) else {
+            return Ok(None);
+        };
+        decode_key(&server_key).map_err(|err| format!("Invalid {} value: {}", SERVER_KEY_VAR, err))?;
+        let keypair = match (env::var(PUBLIC_KEY_VAR), env::var(SECRET_KEY_VAR)) {
+            (Ok(public_key), Ok(secret_key)) => KeyPair::new(public_key, secret_key)?,
+            (Err(_), Err(_)) => KeyPair::generate()?,
+            _ => return Err(format!("{} and {} must be set together", PUBLIC_KEY_VAR, SECRET_KEY_VAR))
+        };
+        ClientKeys::new(server_key, keypair).map(Some)
+    }
+
+    pub(crate) fn configure_client(&self, socket: &zmq::Socket) -> Result<(), String> {
+        check_available()?;
+        socket.set_curve_serverkey(&decode_key(&self.server_key)?).map_err(|err| err.to_string())?;
+        socket.set_curve_publickey(&decode_key(&self.keypair.public_key)?).map_err(|err| err.to_string())?;
+        socket.set_curve_secretkey(&decode_key(self.keypair.secret_key())?).map_err(|err| err.to_string())
+    }
+}
+
+fn check_available() -> Result<(), String> {
+    if is_available() { Ok(()) } else { Err("CURVE is not supported by this build of libzmq".to_owned()) }
+}
+
+// Derives the Z85 public key of a Z85 secret key
+fn public_key_of(secret_key: &str) -> Result<String, String> {
+    check_available()?;
+    ffi::curve_public(secret_key).map_err(|err| format!("Couldn't derive the CURVE public key: {}", err))
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>, String> {
+    match zmq::z85_decode(key) {
+        Ok(bytes) if bytes.len() == KEY_LEN => Ok(bytes),
+        _ => Err(format!("expected {} Z85 characters", KEY_LEN / 4 * 5))
+    }
+}
@@ -0,0 +1,35 @@
+use std::ffi::CString;
+use std::os::raw::{ c_char, c_int, c_void };
+
+// Z85 keys are 40 characters, written with a trailing NUL
+const Z85_KEY_LEN: usize = 40;
+
+// Not wrapped by the zmq crate, libzmq is linked through it
+extern "C" {
+    fn zmq_unbind(socket: *mut c_void, endpoint: *const c_char) -> c_int;
+    fn zmq_curve_public(z85_public_key: *mut c_char, z85_secret_key: *const c_char) -> c_int;
+    fn zmq_errno() -> c_int;
+}
+
+// Closes a bound endpoint along with the connections accepted on it, the requests already received can still be answered
+pub fn unbind(socket: &mut zmq::Socket, endpoint: &str) -> Result<(), zmq::Error> {
+    let endpoint = CString::new(endpoint).map_err(|_| zmq::Error::EINVAL)?;
+    if unsafe { zmq_unbind(socket.as_mut_ptr(), endpoint.as_ptr()) } != 0 {
+        return Err(last_error());
+    }
+    Ok(())
+}
+
+// Derives the Z85 public key of a Z85 secret key
+pub fn curve_public(secret_key: &str) -> Result<String, zmq::Error> {
+    let secret_key = CString::new(secret_key).map_err(|_| zmq::Error::EINVAL)?;
+    let mut public_key = [0u8; Z85_KEY_LEN + 1];
+    if unsafe { zmq_curve_public(public_key.as_mut_ptr() as *mut c_char, secret_key.as_ptr()) } != 0 {
+        return Err(last_error());
+    }
+    Ok(String::from_utf8_lossy(&public_key[..Z85_KEY_LEN]).into_owned())
+}
+
+fn last_error() -> zmq::Error {
+    zmq::Error::from_raw(unsafe { zmq_errno() })
+}
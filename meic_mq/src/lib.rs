@@ -1,57 +1,153 @@
+use auth::Credentials;
+use codec::Codec;
 use context::{ subscriber::SubscriberContext };
-use messages::{ put, get, NetworkTradeable, Message, error, subscribe, unsubscribe };
+use curve::ClientKeys;
+use messages::{ put, get, NetworkTradeable, Message, BrokerReply, DeserializationErrors, error, subscribe, unsubscribe, topic, admin, hello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION };
 
 use lazy_static::lazy_static;
-use zmq;
 use std::sync::Mutex;
+use tracing::{ debug, info, info_span, warn };
 
+pub mod auth;
+pub mod checksum;
+pub mod codec;
+pub mod compression;
 pub mod context;
+pub mod curve;
+pub mod ffi;
 pub mod messages;
 
+const BROKER_ENDPOINT: &str = "tcp://localhost:5555";
+
+// Oldest protocol version that sends post payloads in their own frame
+const PAYLOAD_FRAME_VERSION: u32 = 3;
+
+// Reasons given to the broker when rejecting a post that arrived corrupted or can't be decompressed
+const CHECKSUM_MISMATCH_REASON: &str = "checksum mismatch";
+const UNAVAILABLE_COMPRESSION_REASON: &str = "compression not available";
+
 lazy_static! {
     static ref SOCKET: Mutex<zmq::Socket> = Mutex::new(zmq::Context::new().socket(zmq::REQ).unwrap());
+    static ref CODEC: Mutex<&'static dyn Codec> = Mutex::new(&codec::BsonCodec);
+    static ref CREDENTIALS: Mutex<Option<Credentials>> = Mutex::new(None);
+    // Agreed with the broker by hello, requests are sent in the newest version until then
+    static ref VERSION: Mutex<u32> = Mutex::new(PROTOCOL_VERSION);
+    static ref FEATURES: Mutex<Option<Vec<String>>> = Mutex::new(None);
+}
+
+// Codec used for the following requests, the broker replies with the same one
+pub fn set_codec(name: &str) -> Result<(), String> {
+    let codec = codec::codec_by_name(name).ok_or(format!("Codec '{}' is not available in this build", name))?;
+    *CODEC.lock().unwrap() = codec;
+    Ok(())
+}
+
+// Credentials used to sign the following requests, None sends them unsigned
+pub fn set_credentials(credentials: Option<Credentials>) {
+    *CREDENTIALS.lock().unwrap() = credentials;
+}
+
+// Encrypts the connection to the broker with CURVE, the broker must use the matching server key pair
+pub fn set_curve_keys(keys: &ClientKeys) -> Result<(), String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    keys.configure_client(socket)?;
+    // An already open connection keeps its old security settings, the next request connects again
+    let _ = socket.disconnect(BROKER_ENDPOINT);
+    Ok(())
+}
+
+// Signs requests when MEIC_CLIENT_ID and MEIC_CLIENT_SECRET are set and encrypts the connection when MEIC_SERVER_KEY is set
+pub fn configure_from_env() -> Result<(), String> {
+    set_credentials(Credentials::from_env());
+    match ClientKeys::from_env()? {
+        Some(keys) => set_curve_keys(&keys),
+        None => Ok(())
+    }
+}
+
+// Protocol version the following requests are sent in
+pub fn negotiated_version() -> u32 {
+    *VERSION.lock().unwrap()
+}
+
+// Features the broker agreed to in the last hello, None before it
+pub fn negotiated_features() -> Option<Vec<String>> {
+    FEATURES.lock().unwrap().clone()
 }
 
 pub fn get(sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<Vec<u8>, String> {
+    get_with_metadata(sub_ctx, request).map(|repl| repl.payload)
+}
+
+pub fn get_with_metadata(sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<get::Reply, String> {
+    let _span = info_span!("get", sub_id = %request.sub_id, topic = %request.topic).entered();
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    _get(socket, sub_ctx, request)
+}
+
+// Receives a post without acknowledging it, the caller must either ack or nack it
+pub fn fetch(sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<get::Reply, String> {
+    let _span = info_span!("fetch", sub_id = %request.sub_id, topic = %request.topic).entered();
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    _fetch(socket, sub_ctx, request)
+}
+
+pub fn ack(sub_ctx: &mut SubscriberContext, repl: &get::Reply) -> Result<(), String> {
+    let _span = info_span!("ack", sub_id = %repl.sub_id, post_no = repl.message_no).entered();
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    _ack(socket, sub_ctx, repl)?;
+    sub_ctx.skip_posts(repl.priority, repl.skipped);
+    sub_ctx.increment_next_post_no(repl.priority);
+    Ok(())
+}
+
+// Returns whether the post was moved to the dead-letter topic
+pub fn nack(sub_ctx: &mut SubscriberContext, repl: &get::Reply, reason: String, requeue_delay_ms: Option<u64>) -> Result<bool, String> {
+    let _span = info_span!("nack", sub_id = %repl.sub_id, post_no = repl.message_no).entered();
     let socket: &zmq::Socket = &SOCKET.lock().unwrap();
-    assert!(socket.connect("tcp://localhost:5555").is_ok());
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
 
-    _get(&socket, sub_ctx, request)
+    _nack(socket, sub_ctx, repl, reason, requeue_delay_ms)
 }
 
-fn _get(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<Vec<u8>, String> {
-    socket.send(request.as_message().to_bytes().unwrap(), 0).unwrap();
-    let repl_bytes: Vec<u8> = socket.recv_bytes(0).unwrap();
-    let repl_message: Message = bson::from_slice(repl_bytes.as_slice()).unwrap();
+fn _get(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<get::Reply, String> {
+    let repl = _fetch(socket, sub_ctx, request)?;
+    _ack(socket, sub_ctx, &repl)?;
 
-    // Error Message
-    if repl_message.msg_type == error::REQUEST_HEADER {
-        let error_struct: error::BrokerErrorMessage = bson::from_bson(repl_message.payload).unwrap();
-        match &sub_ctx.known_broker_id {
-            Some(val) => {
+    sub_ctx.skip_posts(repl.priority, repl.skipped);
+    sub_ctx.increment_next_post_no(repl.priority);
+    Ok(repl)
+}
+
+fn _fetch(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &get::Request) -> Result<get::Reply, String> {
+    let message = request.as_message();
+    debug!(correlation_id = %message.correlation_id, "Sending get request");
+    send_message(socket, message);
+    let mut repl = match recv_reply(socket)? {
+        BrokerReply::Get(repl) => repl,
+        BrokerReply::Error(error_struct) => {
+            if let Some(val) = &sub_ctx.known_broker_id {
                 if *val != error_struct.broker_id {
                     return Err("The broker has wiped out its data, need to subscribe again".to_owned())
                 }
-            },
-            _ => {},
-        }
-        match error_struct.error_type {
-            error::BrokerErrorType::InhexistantTopic => panic!("Inexistant topic in get reply"),
-            _ => {}
-        }
-        return Err(error_struct.description);
-    }
-
-    // Unexpected Message Type
-    if repl_message.msg_type != get::REPLY_HEADER {
-        panic!("Unexpected message type '{}' expecting '{}'", repl_message.msg_type, put::REPLY_HEADER);
-    }
+            }
+            debug!(error_type = ?error_struct.error_type, "Broker refused get request");
+            return Err(error_struct.description);
+        },
+        repl => return Err(unexpected_reply(&repl))
+    };
 
     // New broker
-    let repl: get::Reply = bson::from_bson(repl_message.payload).unwrap();
     match &sub_ctx.known_broker_id {
         Some(known_broker_id) => {
             if known_broker_id != &repl.broker_id {
+                warn!(broker_id = %repl.broker_id, "The broker has wiped out its data");
                 return Err("The broker has wiped out its data, need to subscribe again".to_owned());
             }
         } 
@@ -59,137 +155,303 @@ fn _get(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &get::Re
             sub_ctx.known_broker_id = Some(repl.broker_id.clone());
         }
     }
+    debug!(post_no = repl.message_no, priority = repl.priority, skipped = repl.skipped, delivery_attempt = repl.delivery_attempt,
+        publish_correlation_id = %repl.metadata.correlation_id, "Received post");
 
-    // Acknowledgement
-    let ack: get::Ack = get::Ack {
-        sub_id: repl.sub_id,
-        message_no: repl.message_no
-    };
+    // If the message received was not the desired one
+    let expected_post_no = sub_ctx.next_post_no(repl.priority) + repl.skipped;
+    if expected_post_no > repl.message_no {
+        info!(post_no = repl.message_no, expected_post_no, "Received an already read post, acknowledging it again");
+        _ack(socket, sub_ctx, &repl)?;
+        return _fetch(socket, sub_ctx, request);
+    } else if expected_post_no < repl.message_no {
+        warn!(post_no = repl.message_no, expected_post_no, "Received a post ahead of the expected one");
+        return Err(format!("Received post {} while expecting post {}, need to subscribe again", repl.message_no, expected_post_no));
+    }
+
+    // Corrupted on the way, rejected so the broker delivers it again
+    if !checksum::verify(&repl.payload, repl.checksum) {
+        warn!(post_no = repl.message_no, "Post doesn't match its checksum");
+        _nack(socket, sub_ctx, &repl, CHECKSUM_MISMATCH_REASON.to_owned(), None)?;
+        return Err(error::BrokerErrorMessage::new(error::BrokerErrorType::ChecksumMismatch, repl.broker_id).description);
+    }
+
+    // Compressed by the publisher and stored as it is by the broker
+    if let Some(compression) = repl.compression.take() {
+        // Rejected so it counts toward the dead-letter threshold instead of blocking the subscription
+        if !compression.is_available() {
+            warn!(post_no = repl.message_no, ?compression, "Post is compressed with an algorithm this build doesn't support");
+            _nack(socket, sub_ctx, &repl, format!("{}: {:?}", UNAVAILABLE_COMPRESSION_REASON, compression), None)?;
+            return Err(format!("Couldn't decompress post {}: {:?} compression is not available in this build", repl.message_no, compression));
+        }
+        repl.payload = compression.decompress(&repl.payload)
+            .map_err(|err| format!("Couldn't decompress post {}: {}", repl.message_no, err))?;
+    }
 
-    socket.send(ack.as_message().to_bytes().unwrap(), 0).unwrap();
-    let ack_repl_bytes: Vec<u8> = socket.recv_bytes(0).unwrap();
-    let ack_repl_message: Message = bson::from_slice(&ack_repl_bytes.as_slice()).unwrap();
+    Ok(repl)
+}
+
+fn _ack(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, repl: &get::Reply) -> Result<(), String> {
+    let ack: get::Ack = get::Ack::new(repl.sub_id.clone(), repl.message_no, repl.priority);
+    let message = ack.as_message();
+    debug!(correlation_id = %message.correlation_id, post_no = repl.message_no, "Sending ack");
 
-    // Error message
-    if ack_repl_message.msg_type == error::REQUEST_HEADER {
-        let error_struct: error::BrokerErrorMessage = bson::from_bson(ack_repl_message.payload).unwrap();
-        match &sub_ctx.known_broker_id {
-            Some(val) => {
+    send_message(socket, message);
+    match recv_reply(socket)? {
+        BrokerReply::Ack(_) => Ok(()),
+        BrokerReply::Error(error_struct) => {
+            if let Some(val) = &sub_ctx.known_broker_id {
                 if *val != error_struct.broker_id {
                     return Err("The broker has wiped out its data, need to subscribe again".to_owned())
                 }
-            },
-            _ => {},
-        }
-        match error_struct.error_type {
-            error::BrokerErrorType::SubscriberNotRegistered => panic!("Subscriber not registered in ack reply"),
-            error::BrokerErrorType::AckMessageMismatch => panic!("Ack message mismatch: ack message is not on the same message number"),
-            error::BrokerErrorType::NotExpectingAck => panic!("Broker was not expecting ack message"),
-            _ => {}
-        }
-        return Err(error_struct.description);
+            }
+            debug!(error_type = ?error_struct.error_type, "Broker refused ack");
+            Err(error_struct.description)
+        },
+        repl => Err(unexpected_reply(&repl))
     }
+}
 
-    // Unexpected Message Type
-    if ack_repl_message.msg_type != get::ACK_REPLY_HEADER {
-        panic!("Unexpected message type '{}' expecting '{}'", repl_message.msg_type, get::ACK_REPLY_HEADER);
-    }
+fn _nack(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, repl: &get::Reply, reason: String, requeue_delay_ms: Option<u64>) -> Result<bool, String> {
+    let nack: get::Nack = get::Nack::new(repl.sub_id.clone(), repl.message_no, repl.priority, reason, requeue_delay_ms);
+    let message = nack.as_message();
+    debug!(correlation_id = %message.correlation_id, post_no = repl.message_no, reason = %nack.reason, "Sending nack");
 
-    // If the message received was not the desired one
-    if sub_ctx.next_post_no > repl.message_no {
-        return _get(socket, sub_ctx, request);
-    } else if sub_ctx.next_post_no < repl.message_no {
-        panic!("Message number is higher than the one expected to receive");
+    send_message(socket, message);
+    let nack_repl = match recv_reply(socket)? {
+        BrokerReply::Nack(nack_repl) => nack_repl,
+        BrokerReply::Error(error_struct) => {
+            if let Some(val) = &sub_ctx.known_broker_id {
+                if *val != error_struct.broker_id {
+                    return Err("The broker has wiped out its data, need to subscribe again".to_owned())
+                }
+            }
+            return Err(error_struct.description);
+        },
+        repl => return Err(unexpected_reply(&repl))
+    };
+
+    // A dead-lettered post will not be delivered again to this subscriber
+    if nack_repl.dead_lettered {
+        info!(post_no = repl.message_no, "Post was moved to the dead-letter topic");
+        sub_ctx.skip_posts(repl.priority, repl.skipped);
+        sub_ctx.increment_next_post_no(repl.priority);
     }
 
-    sub_ctx.increment_next_post_no();
-    Ok(repl.payload)
+    Ok(nack_repl.dead_lettered)
 }
 
 pub fn put(request: &put::Request) -> Result<(), String> {
+    let _span = info_span!("put", pub_id = %request.pub_id, topic = %request.topic, message_uuid = %request.message_uuid).entered();
     let socket: &zmq::Socket = &SOCKET.lock().unwrap();
-    assert!(socket.connect("tcp://localhost:5555").is_ok());
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
 
-    _put(&socket, request)
+    _put(socket, request)
 }
 
 fn _put(socket: &zmq::Socket, request: &put::Request) -> Result<(), String> {
-    socket.send(request.as_message().to_bytes().unwrap(), 0).unwrap();
-    let repl_bytes = socket.recv_bytes(0).unwrap();
-    let repl_message: Message = bson::from_slice(repl_bytes.as_slice()).unwrap();
-
-    // Error message
-    if repl_message.msg_type == error::REQUEST_HEADER {
-        let error_struct: error::BrokerErrorMessage = bson::from_bson(repl_message.payload).unwrap();
-        return Err(error_struct.description);
+    // Every attempt has its own correlation id, the broker refuses signed requests that reuse one
+    let message = if negotiated_version() >= PAYLOAD_FRAME_VERSION {
+        request.control_header().as_message().with_payload_frame(request.payload.clone())
+    } else {
+        request.as_message()
+    };
+    debug!(correlation_id = %message.correlation_id, "Sending put request");
+    send_message(socket, message);
+    match recv_reply(socket)? {
+        BrokerReply::Put(_) => Ok(()),
+        BrokerReply::Error(error_struct) => {
+            debug!(error_type = ?error_struct.error_type, "Broker refused put request");
+            Err(error_struct.description)
+        },
+        repl => Err(unexpected_reply(&repl))
     }
-
-    // Unexpected message type
-    if repl_message.msg_type != put::REPLY_HEADER {
-        panic!("Unexpected message type '{}' expecting '{}'", repl_message.msg_type, put::REPLY_HEADER);
-    } 
-    
-    Ok(())
 }
 
 pub fn subscribe(sub_ctx: &mut SubscriberContext, request: &subscribe::Request) -> Result<(), String> {
+    let _span = info_span!("subscribe", sub_id = %request.sub_id, topic = %request.topic).entered();
     let socket: &zmq::Socket = &SOCKET.lock().unwrap();
-    assert!(socket.connect("tcp://localhost:5555").is_ok());
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
 
-    _subscribe(&socket, sub_ctx, request)
+    _subscribe(socket, sub_ctx, request)
 }
 
 fn _subscribe(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &subscribe::Request) -> Result<(), String> {
-    socket.send(request.as_message().to_bytes().unwrap(), 0).unwrap();
-    let repl_bytes = socket.recv_bytes(0).unwrap();
-    let repl_msg: Message = bson::from_slice(repl_bytes.as_slice()).unwrap();
-
-    // Error message
-    if repl_msg.msg_type == error::REQUEST_HEADER {
-        let error_struct: error::BrokerErrorMessage = bson::from_bson(repl_msg.payload).unwrap();
-        return Err(error_struct.description);
-    }
-
-    // Unexpected message type
-    if repl_msg.msg_type != subscribe::REPLY_HEADER {
-        panic!("Unexpected message type '{}' expecting '{}'", repl_msg.msg_type, subscribe::REPLY_HEADER);
-    }
-
-    let repl: subscribe::Reply = bson::from_bson(repl_msg.payload).unwrap();
+    let repl = match _request(socket, request.as_message())? {
+        BrokerReply::Subscribe(repl) => repl,
+        repl => return Err(unexpected_reply(&repl))
+    };
 
+    info!(broker_id = %repl.broker_id, post_offsets = ?repl.post_offsets, "Subscribed");
     sub_ctx.known_broker_id = Some(repl.broker_id);
-    sub_ctx.next_post_no = repl.post_offset;
+    sub_ctx.next_post_nos = repl.post_offsets;
 
     Ok(())
 }
 
 pub fn unsubscribe(sub_ctx: &mut SubscriberContext, request: &unsubscribe::Request) -> Result<(), String> {
+    let _span = info_span!("unsubscribe", sub_id = %request.sub_id).entered();
     let socket: &zmq::Socket = &SOCKET.lock().unwrap();
-    assert!(socket.connect("tcp://localhost:5555").is_ok());
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
 
-    _unsubscribe(&socket, sub_ctx, request)
+    _unsubscribe(socket, sub_ctx, request)
 }
 
 fn _unsubscribe(socket: &zmq::Socket, sub_ctx: &mut SubscriberContext, request: &unsubscribe::Request) -> Result<(), String> {
-    socket.send(request.as_message().to_bytes().unwrap(), 0).unwrap();
-    let repl_bytes: Vec<u8> = socket.recv_bytes(0).unwrap();
-    let repl_msg: Message = bson::from_slice(repl_bytes.as_slice()).unwrap();
-
-    // Error message
-    if repl_msg.msg_type == error::REQUEST_HEADER {
-        let error_struct: error::BrokerErrorMessage = bson::from_bson(repl_msg.payload).unwrap();
-        match error_struct.error_type {
+    send_message(socket, request.as_message());
+    match recv_reply(socket)? {
+        BrokerReply::Unsubscribe(_) => {},
+        BrokerReply::Error(error_struct) => match error_struct.error_type {
             error::BrokerErrorType::SubscriberNotRegistered => return Ok(()),
             _ => return Err(error_struct.description)
-        }
-    }
-
-    // Unexpected message type
-    if repl_msg.msg_type != unsubscribe::REPLY_HEADER {
-        panic!("Unexpected message type '{}' expecting '{}'", repl_msg.msg_type, unsubscribe::REPLY_HEADER);
+        },
+        repl => return Err(unexpected_reply(&repl))
     }
 
+    info!("Unsubscribed");
     sub_ctx.known_broker_id = None;
 
     Ok(())
 }
+
+// Agrees with the broker on a protocol version and on the features both sides support
+pub fn hello(features: Vec<String>) -> Result<hello::Reply, String> {
+    let _span = info_span!("hello").entered();
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    let request = hello::Request::new(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, features);
+    let repl = match _request(socket, request.as_message())? {
+        BrokerReply::Hello(repl) => repl,
+        repl => return Err(unexpected_reply(&repl))
+    };
+    info!(broker_id = %repl.broker_id, version = repl.version, features = ?repl.features, "Negotiated protocol");
+    *VERSION.lock().unwrap() = repl.version;
+    *FEATURES.lock().unwrap() = Some(repl.features.clone());
+    Ok(repl)
+}
+
+pub fn create_topic(topic_name: String, settings: topic::TopicSettings) -> Result<(), String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, topic::CreateRequest::new(topic_name, settings).as_message())? {
+        BrokerReply::TopicCreate(_) => Ok(()),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+// Returns the subscribers that were unsubscribed along with the topic
+pub fn delete_topic(topic_name: String) -> Result<Vec<String>, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, topic::DeleteRequest::new(topic_name).as_message())? {
+        BrokerReply::TopicDelete(repl) => Ok(repl.removed_subscribers),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn list_topics() -> Result<Vec<String>, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, topic::ListRequest::new().as_message())? {
+        BrokerReply::TopicList(repl) => Ok(repl.topics),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn describe_topic(topic_name: String) -> Result<topic::DescribeReply, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, topic::DescribeRequest::new(topic_name).as_message())? {
+        BrokerReply::TopicDescribe(repl) => Ok(repl),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn list_subscribers(topic_name: Option<String>) -> Result<Vec<admin::SubscriberInfo>, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, admin::SubscribersRequest::new(topic_name).as_message())? {
+        BrokerReply::AdminSubscribers(repl) => Ok(repl.subscribers),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn evict_subscriber(sub_id: String) -> Result<admin::EvictReply, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, admin::EvictRequest::new(sub_id).as_message())? {
+        BrokerReply::AdminEvict(repl) => Ok(repl),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn purge_topic(topic_name: String) -> Result<admin::PurgeReply, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, admin::PurgeRequest::new(topic_name).as_message())? {
+        BrokerReply::AdminPurge(repl) => Ok(repl),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+pub fn snapshot() -> Result<admin::SnapshotReply, String> {
+    let socket: &zmq::Socket = &SOCKET.lock().unwrap();
+    assert!(socket.connect(BROKER_ENDPOINT).is_ok());
+
+    match _request(socket, admin::SnapshotRequest::new().as_message())? {
+        BrokerReply::AdminSnapshot(repl) => Ok(repl),
+        repl => Err(unexpected_reply(&repl))
+    }
+}
+
+// Sends a request and returns its reply, errors from the broker are returned as their description
+fn _request(socket: &zmq::Socket, request: Message) -> Result<BrokerReply, String> {
+    debug!(msg_type = %request.msg_type, correlation_id = %request.correlation_id, "Sending request");
+    send_message(socket, request);
+    match recv_reply(socket)? {
+        BrokerReply::Error(error_struct) => Err(error_struct.description),
+        repl => Ok(repl)
+    }
+}
+
+// Decodes a reply into whichever message type it holds, payloads sent in their own frame included
+fn recv_reply(socket: &zmq::Socket) -> Result<BrokerReply, String> {
+    let mut repl_message = recv_message(socket);
+    let payload_frame = repl_message.payload_frame.take();
+    let msg_type = repl_message.msg_type.clone();
+    let mut repl = BrokerReply::from_message(repl_message).map_err(|err| match err {
+        DeserializationErrors::IncompatibleMessageType => format!("Unknown reply type '{}'", msg_type),
+        DeserializationErrors::InvalidMessageStructure(details) => format!("Invalid '{}' reply: {}", msg_type, details)
+    })?;
+    if let (BrokerReply::Get(get_repl), Some(payload)) = (&mut repl, payload_frame) {
+        get_repl.payload = payload;
+    }
+    Ok(repl)
+}
+
+fn unexpected_reply(repl: &BrokerReply) -> String {
+    format!("Unexpected reply '{}' from the broker", repl.header())
+}
+
+fn send_message(socket: &zmq::Socket, message: Message) {
+    let message = message.with_version(negotiated_version());
+    let mut frames = codec::encode_frames(*CODEC.lock().unwrap(), message).unwrap();
+    if let Some(credentials) = &*CREDENTIALS.lock().unwrap() {
+        frames = credentials.sign(frames);
+    }
+    socket.send_multipart(frames, 0).unwrap();
+}
+
+fn recv_message(socket: &zmq::Socket) -> Message {
+    let frames = socket.recv_multipart(0).unwrap();
+    let (_, message) = codec::decode_frames(frames).unwrap();
+    message
+}
@@ -1,37 +1,195 @@
 use bson::Bson;
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+// Version of the message envelope and payloads, bumped on every incompatible change
+pub const PROTOCOL_VERSION: u32 = 3;
+// Oldest version still understood, version 1 envelopes have no version field
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 
 pub enum DeserializationErrors {
     IncompatibleMessageType,
     InvalidMessageStructure(String)
 }
 
+// Broker assigned information about a post
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PostMetadata {
+    pub pub_id: String,
+    pub received_at_ms: u64,
+    pub post_no: u64,
+    // Correlation id of the put request that published the post
+    #[serde(default)]
+    pub correlation_id: String
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub msg_type: String,
-    pub payload: Bson
+    pub payload: Bson,
+    // Replies carry the correlation id of their request
+    #[serde(default)]
+    pub correlation_id: String,
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u32,
+    // Raw post payload sent in its own frame after the message, so it is never re-encoded
+    #[serde(skip)]
+    pub payload_frame: Option<Vec<u8>>
+}
+
+fn legacy_protocol_version() -> u32 {
+    1
 }
 
 impl Message {
     fn new(req_type: String, payload: Bson) -> Message {
         Message {
             msg_type: req_type,
-            payload: payload
+            payload,
+            correlation_id: Uuid::new_v4().to_string(),
+            version: PROTOCOL_VERSION,
+            payload_frame: None
         }
     }
 
+    pub fn with_payload_frame(mut self, payload: Vec<u8>) -> Message {
+        self.payload_frame = Some(payload);
+        self
+    }
+
+    // Replies are sent in the version of their request
+    pub fn with_version(mut self, version: u32) -> Message {
+        self.version = version;
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: String) -> Message {
+        self.correlation_id = correlation_id;
+        self
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>, bson::ser::Error> {
         bson::to_vec(self)
     }
 }
 
 pub trait NetworkTradeable<T> {
+    // Message type the struct is sent as
+    const HEADER: &'static str;
+
     fn as_message(&self) -> Message;
     fn from_message(message: Message) -> Result<T, DeserializationErrors>;
-} 
+}
+
+// Declares the header of a message struct and implements NetworkTradeable for it
+macro_rules! message {
+    ($message_type:ident, $header_const:ident = $header:literal) => {
+        pub const $header_const: &str = $header;
+
+        impl $crate::messages::NetworkTradeable<$message_type> for $message_type {
+            const HEADER: &'static str = $header_const;
+
+            fn as_message(&self) -> $crate::messages::Message {
+                $crate::messages::Message::new($header_const.to_string(), bson::to_bson(self).unwrap())
+            }
+
+            fn from_message(message: $crate::messages::Message) -> Result<$message_type, $crate::messages::DeserializationErrors> {
+                if message.msg_type != $header_const {
+                    return Err($crate::messages::DeserializationErrors::IncompatibleMessageType);
+                }
+                bson::from_bson(message.payload)
+                    .map_err(|err| $crate::messages::DeserializationErrors::InvalidMessageStructure(err.to_string()))
+            }
+        }
+    };
+}
+
+// Declares an enum with a variant per message struct, decoded from a message envelope by its header
+macro_rules! registry {
+    ($registry:ident { $($variant:ident($message_type:ty)),* $(,)? }) => {
+        #[derive(Debug)]
+        pub enum $registry {
+            $($variant($message_type)),*
+        }
+
+        impl $registry {
+            pub fn header(&self) -> &'static str {
+                match self {
+                    $($registry::$variant(_) => <$message_type as NetworkTradeable<$message_type>>::HEADER),*
+                }
+            }
+
+            pub fn as_message(&self) -> Message {
+                match self {
+                    $($registry::$variant(message) => message.as_message()),*
+                }
+            }
+
+            pub fn from_message(message: Message) -> Result<$registry, DeserializationErrors> {
+                $registry::from_tagged(&message.msg_type, message.payload)
+            }
+
+            fn from_tagged(msg_type: &str, payload: Bson) -> Result<$registry, DeserializationErrors> {
+                $(if msg_type == <$message_type as NetworkTradeable<$message_type>>::HEADER {
+                    return bson::from_bson(payload).map($registry::$variant)
+                        .map_err(|err| DeserializationErrors::InvalidMessageStructure(err.to_string()));
+                })*
+                Err(DeserializationErrors::IncompatibleMessageType)
+            }
+        }
+
+        $(impl From<$message_type> for $registry {
+            fn from(message: $message_type) -> $registry {
+                $registry::$variant(message)
+            }
+        })*
+    };
+}
 
 pub mod put;
 pub mod get;
 pub mod subscribe;
 pub mod error;
 pub mod unsubscribe;
+pub mod topic;
+pub mod admin;
+pub mod hello;
+
+// Every message a client can send to the broker
+registry!(ClientRequest {
+    Hello(hello::Request),
+    Get(get::Request),
+    Ack(get::Ack),
+    Nack(get::Nack),
+    Put(put::Request),
+    Subscribe(subscribe::Request),
+    Unsubscribe(unsubscribe::Request),
+    TopicCreate(topic::CreateRequest),
+    TopicDelete(topic::DeleteRequest),
+    TopicList(topic::ListRequest),
+    TopicDescribe(topic::DescribeRequest),
+    AdminSubscribers(admin::SubscribersRequest),
+    AdminEvict(admin::EvictRequest),
+    AdminPurge(admin::PurgeRequest),
+    AdminSnapshot(admin::SnapshotRequest)
+});
+
+// Every message the broker can reply with
+registry!(BrokerReply {
+    Hello(hello::Reply),
+    Get(get::Reply),
+    Ack(get::AckReply),
+    Nack(get::NackReply),
+    Put(put::Reply),
+    Subscribe(subscribe::Reply),
+    Unsubscribe(unsubscribe::Reply),
+    TopicCreate(topic::CreateReply),
+    TopicDelete(topic::DeleteReply),
+    TopicList(topic::ListReply),
+    TopicDescribe(topic::DescribeReply),
+    AdminSubscribers(admin::SubscribersReply),
+    AdminEvict(admin::EvictReply),
+    AdminPurge(admin::PurgeReply),
+    AdminSnapshot(admin::SnapshotReply),
+    Error(error::BrokerErrorMessage)
+});
@@ -0,0 +1,151 @@
+use super::subscribe::SessionType;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribersRequest {
+    // Only the subscribers of this topic, all of them when missing
+    #[serde(default)]
+    pub topic: Option<String>
+}
+
+impl SubscribersRequest {
+    pub fn new(topic: Option<String>) -> SubscribersRequest {
+        SubscribersRequest {
+            topic
+        }
+    }
+}
+
+message!(SubscribersRequest, SUBSCRIBERS_HEADER = "ADMIN_SUBS");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriberInfo {
+    pub sub_id: String,
+    pub topic: String,
+    pub status: String,
+    pub session: SessionType,
+    pub last_seen: u64,
+    // Last read post number for each priority
+    pub last_read_posts: HashMap<String, u64>,
+    // Posts published to the topic that the subscriber has not read yet
+    pub lag: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribersReply {
+    pub broker_id: String,
+    pub subscribers: Vec<SubscriberInfo>
+}
+
+impl SubscribersReply {
+    pub fn new(broker_id: String, subscribers: Vec<SubscriberInfo>) -> SubscribersReply {
+        SubscribersReply {
+            broker_id,
+            subscribers
+        }
+    }
+}
+
+message!(SubscribersReply, SUBSCRIBERS_REPLY_HEADER = "ADMIN_SUBS_REPL");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvictRequest {
+    pub sub_id: String
+}
+
+impl EvictRequest {
+    pub fn new(sub_id: String) -> EvictRequest {
+        EvictRequest {
+            sub_id
+        }
+    }
+}
+
+message!(EvictRequest, EVICT_HEADER = "ADMIN_EVICT");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvictReply {
+    pub sub_id: String,
+    pub topic: String,
+    pub broker_id: String
+}
+
+impl EvictReply {
+    pub fn new(sub_id: String, topic: String, broker_id: String) -> EvictReply {
+        EvictReply {
+            sub_id,
+            topic,
+            broker_id
+        }
+    }
+}
+
+message!(EvictReply, EVICT_REPLY_HEADER = "ADMIN_EVICT_REPL");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeRequest {
+    pub topic: String
+}
+
+impl PurgeRequest {
+    pub fn new(topic: String) -> PurgeRequest {
+        PurgeRequest {
+            topic
+        }
+    }
+}
+
+message!(PurgeRequest, PURGE_HEADER = "ADMIN_PURGE");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeReply {
+    pub topic: String,
+    pub broker_id: String,
+    // Retained and scheduled posts that were dropped
+    pub purged_posts: u64
+}
+
+impl PurgeReply {
+    pub fn new(topic: String, broker_id: String, purged_posts: u64) -> PurgeReply {
+        PurgeReply {
+            topic,
+            broker_id,
+            purged_posts
+        }
+    }
+}
+
+message!(PurgeReply, PURGE_REPLY_HEADER = "ADMIN_PURGE_REPL");
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotRequest {}
+
+impl SnapshotRequest {
+    pub fn new() -> SnapshotRequest {
+        SnapshotRequest {}
+    }
+}
+
+message!(SnapshotRequest, SNAPSHOT_HEADER = "ADMIN_SNAPSHOT");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotReply {
+    pub broker_id: String,
+    pub path: String,
+    pub bytes: u64,
+    pub duration_ms: u64
+}
+
+impl SnapshotReply {
+    pub fn new(broker_id: String, path: String, bytes: u64, duration_ms: u64) -> SnapshotReply {
+        SnapshotReply {
+            broker_id,
+            path,
+            bytes,
+            duration_ms
+        }
+    }
+}
+
+message!(SnapshotReply, SNAPSHOT_REPLY_HEADER = "ADMIN_SNAPSHOT_REPL");
@@ -1,8 +1,6 @@
 use serde::{Serialize, Deserialize};
 
-use super::{NetworkTradeable, Message, DeserializationErrors};
-
-pub const REQUEST_HEADER: &str = "ERR";
+use super::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum BrokerErrorType {
@@ -14,7 +12,32 @@ pub enum BrokerErrorType {
     AckMessageMismatch,
     UnknownMessage,
     NoPostsInTopic,
-    NotExpectingAck
+    NotExpectingAck,
+    AwaitingAck,
+    RedeliveryDelayed,
+    TopicAlreadyExists,
+    LimitExceeded,
+    MalformedRequest,
+    UnsupportedVersion,
+    ChecksumMismatch,
+    Unauthenticated,
+    Unauthorized,
+    SnapshotFailed
+}
+
+impl BrokerErrorType {
+    // Protocol version the error type was added in, clients of older versions can't decode it
+    pub fn since_version(&self) -> u32 {
+        match self {
+            BrokerErrorType::SubscriberNotRegistered | BrokerErrorType::SubscriberAlreadyRegistered | BrokerErrorType::InhexistantTopic
+                | BrokerErrorType::DuplicateMessage | BrokerErrorType::TopicMismatch | BrokerErrorType::AckMessageMismatch
+                | BrokerErrorType::UnknownMessage | BrokerErrorType::NoPostsInTopic | BrokerErrorType::NotExpectingAck => 1,
+            BrokerErrorType::AwaitingAck | BrokerErrorType::RedeliveryDelayed | BrokerErrorType::TopicAlreadyExists
+                | BrokerErrorType::LimitExceeded | BrokerErrorType::MalformedRequest | BrokerErrorType::UnsupportedVersion => 2,
+            BrokerErrorType::ChecksumMismatch | BrokerErrorType::Unauthenticated | BrokerErrorType::Unauthorized
+                | BrokerErrorType::SnapshotFailed => 3
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,44 +51,73 @@ impl BrokerErrorMessage {
     pub fn new(error_type: BrokerErrorType, broker_id: String) -> BrokerErrorMessage {
         match error_type {
             BrokerErrorType::SubscriberAlreadyRegistered => BrokerErrorMessage { error_type, broker_id,
-                description: format!("You are already subscribed to a topic") },
+                description: "You are already subscribed to a topic".to_string() },
             BrokerErrorType::SubscriberNotRegistered => BrokerErrorMessage { error_type, broker_id,
-                description: format!("You are not subscribed to that topic") },
+                description: "You are not subscribed to that topic".to_string() },
             BrokerErrorType::InhexistantTopic => BrokerErrorMessage { error_type, broker_id,
-                description: format!("The topic mentioned in the request does not exist") },
+                description: "The topic mentioned in the request does not exist".to_string() },
             BrokerErrorType::DuplicateMessage => BrokerErrorMessage { error_type, broker_id,
-                description: format!("The message you sent is a duplicate message") },
+                description: "The message you sent is a duplicate message".to_string() },
             BrokerErrorType::TopicMismatch => BrokerErrorMessage { error_type, broker_id,
-                description: format!("The topic you are subscribed is different from the one you submitted") },
+                description: "The topic you are subscribed is different from the one you submitted".to_string() },
             BrokerErrorType::AckMessageMismatch => BrokerErrorMessage { error_type, broker_id,
-                description: format!("The ack message id did not match with the last read post") },
+                description: "The ack message id did not match with the last read post".to_string() },
             BrokerErrorType::UnknownMessage => BrokerErrorMessage {error_type, broker_id, 
-                description: format!("Couldn't recognize the type of your message") },
+                description: "Couldn't recognize the type of your message".to_string() },
             BrokerErrorType::NoPostsInTopic => BrokerErrorMessage {error_type, broker_id, 
-                description: format!("There are still no posts in that topic") },
+                description: "There are still no posts in that topic".to_string() },
             BrokerErrorType::NotExpectingAck => BrokerErrorMessage {error_type, broker_id,
-                description: format!("The broker was not expecting an ACK message") }
+                description: "The broker was not expecting an ACK message".to_string() },
+            BrokerErrorType::AwaitingAck => BrokerErrorMessage {error_type, broker_id,
+                description: "The last post delivered to you is still waiting for an ACK".to_string() },
+            BrokerErrorType::RedeliveryDelayed => BrokerErrorMessage {error_type, broker_id,
+                description: "The rejected post will only be delivered again after its requeue delay".to_string() },
+            BrokerErrorType::TopicAlreadyExists => BrokerErrorMessage {error_type, broker_id,
+                description: "The topic mentioned in the request already exists".to_string() },
+            BrokerErrorType::LimitExceeded => BrokerErrorMessage {error_type, broker_id,
+                description: "The request goes over a limit configured on the broker".to_string() },
+            BrokerErrorType::MalformedRequest => BrokerErrorMessage {error_type, broker_id,
+                description: "The broker couldn't decode your request".to_string() },
+            BrokerErrorType::UnsupportedVersion => BrokerErrorMessage {error_type, broker_id,
+                description: format!("The broker only speaks protocol versions {} to {}", MIN_PROTOCOL_VERSION, PROTOCOL_VERSION) },
+            BrokerErrorType::ChecksumMismatch => BrokerErrorMessage {error_type, broker_id,
+                description: "The payload doesn't match its checksum, it was corrupted on the way".to_string() },
+            BrokerErrorType::Unauthenticated => BrokerErrorMessage {error_type, broker_id,
+                description: "The broker couldn't authenticate your request, check your client id and secret".to_string() },
+            BrokerErrorType::Unauthorized => BrokerErrorMessage {error_type, broker_id,
+                description: "You are not allowed to do that on this topic".to_string() },
+            BrokerErrorType::SnapshotFailed => BrokerErrorMessage {error_type, broker_id,
+                description: "The broker couldn't save its state".to_string() }
         }
     }
 
+    // Carries the reason the request couldn't be decoded
+    pub fn malformed(broker_id: String, details: &str) -> BrokerErrorMessage {
+        let mut error = BrokerErrorMessage::new(BrokerErrorType::MalformedRequest, broker_id);
+        error.description = format!("{}: {}", error.description, details);
+        error
+    }
 
-}
-
-impl NetworkTradeable<BrokerErrorMessage> for BrokerErrorMessage {
-    fn as_message(&self) -> Message {
-        Message {
-            msg_type: REQUEST_HEADER.to_string(),
-            payload: bson::to_bson(self).unwrap()
-        }
+    // Carries the reason the state couldn't be saved
+    pub fn snapshot_failed(broker_id: String, details: &str) -> BrokerErrorMessage {
+        let mut error = BrokerErrorMessage::new(BrokerErrorType::SnapshotFailed, broker_id);
+        error.description = format!("{}: {}", error.description, details);
+        error
     }
 
-    fn from_message(message: Message) -> Result<BrokerErrorMessage, DeserializationErrors> {
-        if message.msg_type != REQUEST_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
+    // Closest error type a client of the given version can decode, the description still gives the actual reason
+    pub fn for_version(mut self, version: u32) -> BrokerErrorMessage {
+        if self.error_type.since_version() <= version {
+            return self;
         }
+        self.error_type = match self.error_type {
+            // Nothing can be delivered yet, as if the topic had no new posts
+            BrokerErrorType::AwaitingAck | BrokerErrorType::RedeliveryDelayed => BrokerErrorType::NoPostsInTopic,
+            _ if BrokerErrorType::MalformedRequest.since_version() <= version => BrokerErrorType::MalformedRequest,
+            _ => BrokerErrorType::UnknownMessage
+        };
+        self
     }
 }
+
+message!(BrokerErrorMessage, REQUEST_HEADER = "ERR");
@@ -1,30 +1,58 @@
-use super::{NetworkTradeable, Message, DeserializationErrors};
+use super::PostMetadata;
+use crate::compression::Compression;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
-pub const REQUEST_HEADER: &str = "GET";
-pub const REPLY_HEADER: &str = "GET_REPL";
-pub const ACK_HEADER: &str = "GET_ACK";
-pub const ACK_REPLY_HEADER: &str = "GET_ACK_REPL";
+// Headers added to the posts moved to a dead-letter topic
+pub const DEAD_LETTER_REASON_HEADER: &str = "x-dead-letter-reason";
+pub const DEAD_LETTER_SUB_ID_HEADER: &str = "x-dead-letter-sub-id";
+pub const ORIGINAL_TOPIC_HEADER: &str = "x-original-topic";
+pub const ORIGINAL_POST_NO_HEADER: &str = "x-original-post-no";
+pub const DELIVERY_ATTEMPTS_HEADER: &str = "x-delivery-attempts";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub sub_id: String,
-    pub topic: String
+    pub topic: String,
+    // Asks for the payload of the reply in its own frame
+    #[serde(default)]
+    pub payload_frame: bool
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Reply {
     pub sub_id: String,
     pub message_no: u64,
+    // Each priority has its own post numbering
+    #[serde(default)]
+    pub priority: u8,
     pub broker_id: String,
     #[serde(with = "serde_bytes")]
     pub payload: Vec<u8>,
+    // Number of expired or dead-lettered posts skipped before this one
+    #[serde(default)]
+    pub skipped: u64,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub metadata: PostMetadata,
+    // How many times this post was delivered to the subscriber, including this one
+    #[serde(default)]
+    pub delivery_attempt: u32,
+    // Algorithm the payload was compressed with by its publisher
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    // Checksum of the payload as it was published
+    #[serde(default)]
+    pub checksum: Option<u32>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ack {
     pub sub_id: String,
-    pub message_no: u64
+    pub message_no: u64,
+    #[serde(default)]
+    pub priority: u8
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,11 +61,31 @@ pub struct AckReply {
     pub message_no: u64
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Nack {
+    pub sub_id: String,
+    pub message_no: u64,
+    #[serde(default)]
+    pub priority: u8,
+    pub reason: String,
+    // Time to wait before the post is delivered again, immediately if None
+    #[serde(default)]
+    pub requeue_delay_ms: Option<u64>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NackReply {
+    pub sub_id: String,
+    pub message_no: u64,
+    pub dead_lettered: bool
+}
+
 impl Request {
     pub fn new(sub_id: String, topic: String) -> Request {
         Request {
             sub_id,
-            topic
+            topic,
+            payload_frame: true
         }
     }
 }
@@ -47,17 +95,31 @@ impl Reply {
         Reply {
             sub_id,
             message_no,
+            priority: 0,
             broker_id,
-            payload
+            payload,
+            skipped: 0,
+            headers: HashMap::new(),
+            metadata: PostMetadata::default(),
+            delivery_attempt: 1,
+            compression: None,
+            checksum: None
         }
     }
+
+    // Exponential backoff to use as requeue delay when rejecting this post
+    pub fn backoff_delay_ms(&self, base_delay_ms: u64, max_delay_ms: u64) -> u64 {
+        let exponent = self.delivery_attempt.saturating_sub(1).min(63);
+        base_delay_ms.saturating_mul(1u64 << exponent).min(max_delay_ms)
+    }
 }
 
 impl Ack {
-    pub fn new(sub_id: String, message_no: u64) -> Ack {
+    pub fn new(sub_id: String, message_no: u64, priority: u8) -> Ack {
         Ack {
             sub_id,
-            message_no
+            message_no,
+            priority
         }
     }
 }
@@ -71,66 +133,31 @@ impl AckReply {
     }
 }
 
-impl NetworkTradeable<Request> for Request {
-    fn as_message(&self) -> Message {
-        Message::new(REQUEST_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Request, DeserializationErrors> {
-        if message.msg_type != REQUEST_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
-
-impl NetworkTradeable<Reply> for Reply {
-    fn as_message(&self) -> Message {
-        Message::new(REPLY_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Reply, DeserializationErrors> {
-        if message.msg_type != REPLY_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
+impl Nack {
+    pub fn new(sub_id: String, message_no: u64, priority: u8, reason: String, requeue_delay_ms: Option<u64>) -> Nack {
+        Nack {
+            sub_id,
+            message_no,
+            priority,
+            reason,
+            requeue_delay_ms
         }
     }
 }
 
-impl NetworkTradeable<Ack> for Ack {
-    fn as_message(&self) -> Message {
-        Message::new(ACK_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Ack, DeserializationErrors> {
-        if message.msg_type != ACK_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
+impl NackReply {
+    pub fn new(sub_id: String, message_no: u64, dead_lettered: bool) -> NackReply {
+        NackReply {
+            sub_id,
+            message_no,
+            dead_lettered
         }
     }
 }
 
-impl NetworkTradeable<AckReply> for AckReply {
-    fn as_message(&self) -> Message {
-        Message::new(ACK_REPLY_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<AckReply, DeserializationErrors> {
-        if message.msg_type != ACK_REPLY_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
+message!(Request, REQUEST_HEADER = "GET");
+message!(Reply, REPLY_HEADER = "GET_REPL");
+message!(Ack, ACK_HEADER = "GET_ACK");
+message!(AckReply, ACK_REPLY_HEADER = "GET_ACK_REPL");
+message!(Nack, NACK_HEADER = "GET_NACK");
+message!(NackReply, NACK_REPLY_HEADER = "GET_NACK_REPL");
@@ -0,0 +1,54 @@
+use serde::{Serialize, Deserialize};
+
+// Optional protocol features, announced by name so unknown ones can be ignored
+pub const FEATURE_HEADERS: &str = "headers";
+pub const FEATURE_COMPRESSION: &str = "compression";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    // Protocol versions the client is able to speak
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: Vec<String>
+}
+
+impl Request {
+    pub fn new(min_version: u32, max_version: u32, features: Vec<String>) -> Request {
+        Request {
+            min_version,
+            max_version,
+            features
+        }
+    }
+}
+
+message!(Request, REQUEST_HEADER = "HELLO");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reply {
+    pub broker_id: String,
+    // Highest protocol version both sides speak
+    pub version: u32,
+    // Features requested by the client that the broker supports
+    pub features: Vec<String>,
+    // Codecs the broker accepts requests in
+    #[serde(default)]
+    pub codecs: Vec<String>
+}
+
+impl Reply {
+    pub fn new(broker_id: String, version: u32, features: Vec<String>, codecs: Vec<String>) -> Reply {
+        Reply {
+            broker_id,
+            version,
+            features,
+            codecs
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+}
+
+message!(Reply, REPLY_HEADER = "HELLO_REPL");
@@ -1,9 +1,9 @@
-use super::{NetworkTradeable, Message, DeserializationErrors};
+use crate::checksum;
+use crate::compression::Compression;
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
-
-pub const REQUEST_HEADER: &str = "PUT";
-pub const REPLY_HEADER: &str = "PUT_REPL";
+use std::collections::HashMap;
+use std::time::{ SystemTime, UNIX_EPOCH };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
@@ -11,37 +11,104 @@ pub struct Request {
     pub topic: String,
     pub message_uuid: String,
     #[serde(with = "serde_bytes")]
-    pub payload: Vec<u8>
+    pub payload: Vec<u8>,
+    // Unix timestamp (in seconds) after which the post is no longer delivered
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // Unix timestamp (in milliseconds) before which the post is not visible to subscribers
+    #[serde(default)]
+    pub deliver_at_ms: Option<u64>,
+    // Posts with higher priority are delivered first
+    #[serde(default)]
+    pub priority: u8,
+    // Algorithm the payload was compressed with, the broker stores it as it is
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    // Checksum of the payload, verified by the broker when the post is received
+    #[serde(default)]
+    pub checksum: Option<u32>
 }
 
 impl Request {
     pub fn new(pub_id: String, topic: String, payload: Vec<u8>) -> Request {
         let uuid: Uuid = Uuid::new_v4();
+        let checksum = checksum::compute(&payload);
         Request {
-            pub_id: pub_id,
-            topic: topic,
+            pub_id,
+            topic,
             message_uuid: uuid.to_string(),
-            payload: payload
+            payload,
+            expires_at: None,
+            headers: HashMap::new(),
+            deliver_at_ms: None,
+            priority: 0,
+            compression: None,
+            checksum: Some(checksum)
         }
     }
-}
 
-impl NetworkTradeable<Request> for Request {
-    fn as_message(&self) -> Message {
-        Message::new(REQUEST_HEADER.to_string(), bson::to_bson(self).unwrap())
+    pub fn with_priority(mut self, priority: u8) -> Request {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_delay(self, delay_ms: u64) -> Request {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        self.with_deliver_at(now_ms.saturating_add(delay_ms))
+    }
+
+    pub fn with_deliver_at(mut self, deliver_at_ms: u64) -> Request {
+        self.deliver_at_ms = Some(deliver_at_ms);
+        self
     }
 
-    fn from_message(message: Message) -> Result<Request, DeserializationErrors> {
-        if message.msg_type != REQUEST_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
+    // Copy of the request without its payload, which is then sent in its own frame
+    pub fn control_header(&self) -> Request {
+        Request {
+            pub_id: self.pub_id.clone(),
+            topic: self.topic.clone(),
+            message_uuid: self.message_uuid.clone(),
+            payload: Vec::new(),
+            expires_at: self.expires_at,
+            headers: self.headers.clone(),
+            deliver_at_ms: self.deliver_at_ms,
+            priority: self.priority,
+            compression: self.compression,
+            checksum: self.checksum
         }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
+    }
+
+    pub fn with_header(mut self, key: String, value: String) -> Request {
+        self.headers.insert(key, value);
+        self
+    }
+
+    pub fn with_ttl(self, ttl_secs: u64) -> Request {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.with_expiry(now + ttl_secs)
+    }
+
+    pub fn with_expiry(mut self, expires_at: u64) -> Request {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    // Compresses the payload unless it is smaller than the threshold
+    pub fn with_compression(mut self, compression: Compression, threshold_bytes: usize) -> Result<Request, String> {
+        if self.compression.is_some() || self.payload.len() < threshold_bytes {
+            return Ok(self);
         }
+        self.payload = compression.compress(&self.payload)?;
+        self.compression = Some(compression);
+        self.checksum = Some(checksum::compute(&self.payload));
+        Ok(self)
     }
 }
 
+message!(Request, REQUEST_HEADER = "PUT");
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Reply {
     pub message_uuid: String,
@@ -52,25 +119,11 @@ pub struct Reply {
 impl Reply {
     pub fn new(message_uuid: String, topic: String, broker_id: String) -> Reply {
         Reply {
-            message_uuid: message_uuid,
-            topic: topic,
-            broker_id: broker_id
+            message_uuid,
+            topic,
+            broker_id
         }
     }
 }
 
-impl NetworkTradeable<Reply> for Reply {
-    fn as_message(&self) -> Message {
-        Message::new(REPLY_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Reply, DeserializationErrors> {
-        if message.msg_type != REPLY_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
+message!(Reply, REPLY_HEADER = "PUT_REPL");
@@ -1,71 +1,95 @@
-use super::{NetworkTradeable, Message, DeserializationErrors};
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
-pub const REQUEST_HEADER: &str = "SUB";
-pub const REPLY_HEADER: &str = "SUB_REPL";
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SessionType {
+    #[default]
+    Durable,
+    Ephemeral
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub sub_id: String,
-    pub topic: String
+    pub topic: String,
+    #[serde(default)]
+    pub session: SessionType,
+    // Seconds of inactivity after which the broker evicts the subscriber, broker default if None
+    #[serde(default)]
+    pub session_timeout: Option<u64>,
+    // Posts nacked this many times are moved to the dead-letter topic, unlimited if None
+    #[serde(default)]
+    pub max_delivery_attempts: Option<u32>,
+    #[serde(default)]
+    pub dead_letter_topic: Option<String>,
+    // Time a delivered post waits for its ACK before it can be delivered again, broker default if None
+    #[serde(default)]
+    pub visibility_timeout_ms: Option<u64>
 }
 
 impl Request {
     pub fn new(sub_id: String, topic: String) -> Request {
         Request {
-            sub_id: sub_id,
-            topic: topic
+            sub_id,
+            topic,
+            session: SessionType::Durable,
+            session_timeout: None,
+            max_delivery_attempts: None,
+            dead_letter_topic: None,
+            visibility_timeout_ms: None
         }
     }
-}
 
-impl NetworkTradeable<Request> for Request {
-    fn as_message(&self) -> Message {
-        Message::new(REQUEST_HEADER.to_string(), bson::to_bson(self).unwrap())
+    pub fn with_session(sub_id: String, topic: String, session: SessionType, session_timeout: Option<u64>) -> Request {
+        Request {
+            sub_id,
+            topic,
+            session,
+            session_timeout,
+            max_delivery_attempts: None,
+            dead_letter_topic: None,
+            visibility_timeout_ms: None
+        }
     }
 
-    fn from_message(message: Message) -> Result<Request, DeserializationErrors> {
-        if message.msg_type != REQUEST_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
+    pub fn with_dead_letter(mut self, max_delivery_attempts: u32, dead_letter_topic: Option<String>) -> Request {
+        self.max_delivery_attempts = Some(max_delivery_attempts);
+        self.dead_letter_topic = dead_letter_topic;
+        self
+    }
+
+    pub fn with_visibility_timeout(mut self, visibility_timeout_ms: u64) -> Request {
+        self.visibility_timeout_ms = Some(visibility_timeout_ms);
+        self
     }
 }
 
+message!(Request, REQUEST_HEADER = "SUB");
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Reply {
     pub sub_id: String,
     pub topic: String,
     pub broker_id: String,
-    pub post_offset: u64
+    pub post_offset: u64,
+    #[serde(default)]
+    pub session_timeout: u64,
+    // First post number to read for each priority already used in the topic
+    #[serde(default)]
+    pub post_offsets: HashMap<String, u64>
 }
 
 impl Reply {
-    pub fn new(sub_id: String, topic: String, broker_id: String, post_offset: u64) -> Reply {
+    pub fn new(sub_id: String, topic: String, broker_id: String, post_offsets: HashMap<String, u64>, session_timeout: u64) -> Reply {
         Reply {
-            sub_id: sub_id,
-            topic: topic,
-            broker_id: broker_id,
-            post_offset: post_offset
+            sub_id,
+            topic,
+            broker_id,
+            post_offset: *post_offsets.get("0").unwrap_or(&1),
+            session_timeout,
+            post_offsets
         }
     }
 }
 
-impl NetworkTradeable<Reply> for Reply {
-    fn as_message(&self) -> Message {
-        Message::new(REPLY_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Reply, DeserializationErrors> {
-        if message.msg_type != REPLY_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
+message!(Reply, REPLY_HEADER = "SUB_REPL");
@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+// Defaults applied by the broker to the posts and subscriptions of a topic
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TopicSettings {
+    #[serde(default)]
+    pub default_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub max_delivery_attempts: Option<u32>
+}
+
+impl TopicSettings {
+    pub fn with_default_ttl(mut self, ttl_secs: u64) -> TopicSettings {
+        self.default_ttl_secs = Some(ttl_secs);
+        self
+    }
+
+    pub fn with_max_delivery_attempts(mut self, max_delivery_attempts: u32) -> TopicSettings {
+        self.max_delivery_attempts = Some(max_delivery_attempts);
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRequest {
+    pub topic: String,
+    #[serde(default)]
+    pub settings: TopicSettings
+}
+
+impl CreateRequest {
+    pub fn new(topic: String, settings: TopicSettings) -> CreateRequest {
+        CreateRequest {
+            topic,
+            settings
+        }
+    }
+}
+
+message!(CreateRequest, CREATE_HEADER = "TOPIC_CREATE");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateReply {
+    pub topic: String,
+    pub broker_id: String
+}
+
+impl CreateReply {
+    pub fn new(topic: String, broker_id: String) -> CreateReply {
+        CreateReply {
+            topic,
+            broker_id
+        }
+    }
+}
+
+message!(CreateReply, CREATE_REPLY_HEADER = "TOPIC_CREATE_REPL");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteRequest {
+    pub topic: String
+}
+
+impl DeleteRequest {
+    pub fn new(topic: String) -> DeleteRequest {
+        DeleteRequest {
+            topic
+        }
+    }
+}
+
+message!(DeleteRequest, DELETE_HEADER = "TOPIC_DELETE");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteReply {
+    pub topic: String,
+    pub broker_id: String,
+    // Subscribers that were unsubscribed along with the topic
+    pub removed_subscribers: Vec<String>
+}
+
+impl DeleteReply {
+    pub fn new(topic: String, broker_id: String, removed_subscribers: Vec<String>) -> DeleteReply {
+        DeleteReply {
+            topic,
+            broker_id,
+            removed_subscribers
+        }
+    }
+}
+
+message!(DeleteReply, DELETE_REPLY_HEADER = "TOPIC_DELETE_REPL");
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListRequest {}
+
+impl ListRequest {
+    pub fn new() -> ListRequest {
+        ListRequest {}
+    }
+}
+
+message!(ListRequest, LIST_HEADER = "TOPIC_LIST");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListReply {
+    pub broker_id: String,
+    pub topics: Vec<String>
+}
+
+impl ListReply {
+    pub fn new(broker_id: String, topics: Vec<String>) -> ListReply {
+        ListReply {
+            broker_id,
+            topics
+        }
+    }
+}
+
+message!(ListReply, LIST_REPLY_HEADER = "TOPIC_LIST_REPL");
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DescribeRequest {
+    pub topic: String
+}
+
+impl DescribeRequest {
+    pub fn new(topic: String) -> DescribeRequest {
+        DescribeRequest {
+            topic
+        }
+    }
+}
+
+message!(DescribeRequest, DESCRIBE_HEADER = "TOPIC_DESCRIBE");
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DescribeReply {
+    pub topic: String,
+    pub broker_id: String,
+    pub settings: TopicSettings,
+    // Number of posts published so far for each priority
+    pub post_counters: HashMap<String, u64>,
+    // Posts still held by the broker, scheduled ones included
+    pub retained_posts: u64,
+    pub scheduled_posts: u64,
+    pub byte_size: u64,
+    // Last read post number for each priority of every subscriber
+    pub subscriber_offsets: HashMap<String, HashMap<String, u64>>
+}
+
+impl DescribeReply {
+    pub fn new(topic: String, broker_id: String, settings: TopicSettings) -> DescribeReply {
+        DescribeReply {
+            topic,
+            broker_id,
+            settings,
+            ..Default::default()
+        }
+    }
+}
+
+message!(DescribeReply, DESCRIBE_REPLY_HEADER = "TOPIC_DESCRIBE_REPL");
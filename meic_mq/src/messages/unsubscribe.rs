@@ -1,9 +1,5 @@
-use super::{NetworkTradeable, Message, DeserializationErrors};
 use serde::{Serialize, Deserialize};
 
-pub const REQUEST_HEADER: &str = "UNSUB";
-pub const REPLY_HEADER: &str = "UNSUB_REPL";
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub sub_id: String
@@ -17,21 +13,7 @@ impl Request {
     }
 }
 
-impl NetworkTradeable<Request> for Request {
-    fn as_message(&self) -> Message {
-        Message::new(REQUEST_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Request, DeserializationErrors> {
-        if message.msg_type != REQUEST_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
+message!(Request, REQUEST_HEADER = "UNSUB");
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Reply {
@@ -42,24 +24,10 @@ pub struct Reply {
 impl Reply {
     pub fn new(sub_id: String, broker_id: String) -> Reply {
         Reply {
-            sub_id: sub_id,
-            broker_id: broker_id
+            sub_id,
+            broker_id
         }
     }
 }
 
-impl NetworkTradeable<Reply> for Reply {
-    fn as_message(&self) -> Message {
-        Message::new(REPLY_HEADER.to_string(), bson::to_bson(self).unwrap())
-    }
-
-    fn from_message(message: Message) -> Result<Reply, DeserializationErrors> {
-        if message.msg_type != REPLY_HEADER {
-            return Err(DeserializationErrors::IncompatibleMessageType);
-        }
-        match bson::from_bson(message.payload) {
-            Ok(val) => Ok(val),
-            Err(err) => Err(DeserializationErrors::InvalidMessageStructure(err.to_string()))
-        }
-    }
-}
+message!(Reply, REPLY_HEADER = "UNSUB_REPL");